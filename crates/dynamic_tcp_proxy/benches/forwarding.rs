@@ -0,0 +1,157 @@
+//! Throughput and CPU time of a bulk upload through the proxy, copied through user space
+//! and spliced. The CPU time covers the whole process, the benchmark's client and upstream
+//! included, so only the difference between both paths is meaningful.
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use criterion::measurement::Measurement;
+#[cfg(target_os = "linux")]
+use criterion::measurement::{ValueFormatter, WallTime};
+use criterion::{criterion_group, criterion_main, BenchmarkGroup, Criterion, Throughput};
+use dynamic_tcp_proxy::{DynamicProxy, ForwardTarget, ListenerOptions, ProxyConfig};
+
+const TRANSFER: usize = 64 * 1024 * 1024;
+
+struct Setup {
+    proxy: DynamicProxy,
+    listen_port: u16,
+    upstream_port: u16,
+}
+
+impl Setup {
+    fn new() -> Self {
+        let (proxy, _) = DynamicProxy::initiate().expect("Cannot start the proxy");
+        Self {
+            proxy,
+            listen_port: free_port(),
+            upstream_port: spawn_sink(),
+        }
+    }
+
+    fn forward(&self, zero_copy: bool) {
+        let target = ForwardTarget {
+            domain: "localhost".to_owned(),
+            port: self.upstream_port,
+            ..Default::default()
+        };
+        let options = ListenerOptions {
+            zero_copy,
+            ..Default::default()
+        };
+        self.proxy
+            .update(ProxyConfig::forward(self.listen_port, target, options))
+            .expect("Cannot update the proxy");
+        // Wait for the listener to be bound
+        while TcpStream::connect(("127.0.0.1", self.listen_port)).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Sends `TRANSFER` bytes through the proxy and waits for the upstream to have read them.
+    fn upload(&self, data: &[u8]) {
+        let mut stream =
+            TcpStream::connect(("127.0.0.1", self.listen_port)).expect("Cannot connect");
+        stream.write_all(data).expect("Cannot write");
+        stream.shutdown(Shutdown::Write).expect("Cannot shut down");
+        let mut received = [0; 8];
+        stream.read_exact(&mut received).expect("Cannot read");
+        assert_eq!(u64::from_be_bytes(received), data.len() as u64);
+    }
+}
+
+fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Cannot bind");
+    listener.local_addr().expect("No local address").port()
+}
+
+/// Upstream that reads connections to the end and answers with the number of bytes read.
+fn spawn_sink() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Cannot bind");
+    let port = listener.local_addr().expect("No local address").port();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let read = std::io::copy(&mut stream, &mut std::io::sink()).unwrap_or(0);
+                let _ = stream.write_all(&read.to_be_bytes());
+            });
+        }
+    });
+    port
+}
+
+fn compare<M: Measurement>(mut group: BenchmarkGroup<'_, M>, setup: &Setup) {
+    let data = vec![0x5a; TRANSFER];
+    group.throughput(Throughput::Bytes(TRANSFER as u64));
+    group.sample_size(20);
+    for (name, zero_copy) in [("copy", false), ("splice", true)] {
+        setup.forward(zero_copy);
+        group.bench_function(name, |b| b.iter(|| setup.upload(&data)));
+    }
+    group.finish();
+}
+
+fn forwarding(c: &mut Criterion) {
+    let setup = Setup::new();
+    compare(c.benchmark_group("forwarding"), &setup);
+
+    #[cfg(target_os = "linux")]
+    {
+        let mut cpu = Criterion::default().with_measurement(CpuTime);
+        compare(cpu.benchmark_group("forwarding_cpu"), &setup);
+    }
+}
+
+/// User and system time used by the process.
+#[cfg(target_os = "linux")]
+struct CpuTime;
+
+#[cfg(target_os = "linux")]
+impl CpuTime {
+    fn now() -> Duration {
+        let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+        let usage = unsafe {
+            libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr());
+            usage.assume_init()
+        };
+        let time =
+            |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1_000);
+        time(usage.ru_utime) + time(usage.ru_stime)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Measurement for CpuTime {
+    type Intermediate = Duration;
+    type Value = Duration;
+
+    fn start(&self) -> Self::Intermediate {
+        Self::now()
+    }
+
+    fn end(&self, started: Self::Intermediate) -> Self::Value {
+        Self::now() - started
+    }
+
+    fn add(&self, v1: &Self::Value, v2: &Self::Value) -> Self::Value {
+        *v1 + *v2
+    }
+
+    fn zero(&self) -> Self::Value {
+        Duration::ZERO
+    }
+
+    fn to_f64(&self, value: &Self::Value) -> f64 {
+        value.as_nanos() as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        // Values are nanoseconds, as for wall time
+        WallTime.formatter()
+    }
+}
+
+criterion_group!(benches, forwarding);
+criterion_main!(benches);
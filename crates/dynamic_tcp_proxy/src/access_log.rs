@@ -0,0 +1,330 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Result, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use tracing::{error, info};
+
+use crate::config::AccessLogOptions;
+
+/// Handle to the access log written by a background thread, the file is closed
+/// once every clone of the handle is dropped.
+#[derive(Clone)]
+pub(crate) struct AccessLog {
+    messages: Sender<Message>,
+    pub(crate) options: AccessLogOptions,
+    /// Folder the log is written to
+    pub(crate) directory: PathBuf,
+}
+
+enum Message {
+    Line(String),
+    Options(AccessLogOptions),
+}
+
+impl AccessLog {
+    pub(crate) fn create(
+        directory: &Path,
+        listen_port: u16,
+        options: AccessLogOptions,
+    ) -> Result<(Self, PathBuf)> {
+        let path = directory.join(format!("port_switch_{listen_port}_access.log"));
+        let mut file = Rotating::open(path.clone(), options)?;
+
+        let (messages_tx, messages_rx) = channel::<Message>();
+        thread::Builder::new()
+            .name("access_log_writer".to_string())
+            .spawn(move || {
+                // Lines are dropped while the file cannot be written, each new one retrying it
+                let mut failing = false;
+                for message in messages_rx {
+                    let line = match message {
+                        Message::Line(line) => line,
+                        Message::Options(options) => {
+                            file.options = options;
+                            continue;
+                        }
+                    };
+                    match file.write(line.as_bytes()) {
+                        Ok(()) if failing => {
+                            failing = false;
+                            info!(path = %file.path.display(), "Access log is written again");
+                        }
+                        Ok(()) => {}
+                        Err(e) if !failing => {
+                            failing = true;
+                            error!(
+                                path = %file.path.display(),
+                                error = %e,
+                                "Failed to write access log"
+                            );
+                        }
+                        Err(_) => {}
+                    }
+                }
+            })?;
+
+        let log = Self {
+            messages: messages_tx,
+            options,
+            directory: directory.to_path_buf(),
+        };
+        Ok((log, path))
+    }
+
+    /// Rotates by `options` from the next line on, keeping the file it writes to.
+    pub(crate) fn set_options(&mut self, options: AccessLogOptions) {
+        self.options = options;
+        let _ = self.messages.send(Message::Options(options));
+    }
+
+    /// Logs a closed connection along with the bytes it moved each way.
+    pub(crate) fn record(
+        &self,
+        entry: &Entry,
+        (from_client, from_server): (u64, u64),
+        close: &str,
+    ) {
+        let upstream = entry
+            .upstream
+            .map_or_else(|| "-".to_owned(), |addr| addr.to_string());
+        let line = format!(
+            "{} listener={} client={} target={:?} upstream={} duration_ms={} \
+             from_client={} from_server={} close={:?}\n",
+            timestamp(SystemTime::now()),
+            entry.listener,
+            entry.client,
+            entry.target,
+            upstream,
+            entry.started.elapsed().as_millis(),
+            from_client,
+            from_server,
+            close
+        );
+        let _ = self.messages.send(Message::Line(line));
+    }
+}
+
+/// What is known about a connection by the time it was handed to a target.
+pub(crate) struct Entry {
+    pub(crate) listener: u16,
+    pub(crate) client: SocketAddr,
+    pub(crate) target: String,
+    pub(crate) upstream: Option<SocketAddr>,
+    pub(crate) started: Instant,
+}
+
+/// Log file moved aside to `<path>.1` once it would grow past the size limit, older
+/// files shifting up to `<path>.<keep>` and the oldest one dropped.
+struct Rotating {
+    path: PathBuf,
+    options: AccessLogOptions,
+    /// Missing after a failed write, opened again by the next one
+    file: Option<File>,
+    size: u64,
+}
+
+impl Rotating {
+    fn open(path: PathBuf, options: AccessLogOptions) -> Result<Self> {
+        let mut rotating = Self {
+            path,
+            options,
+            file: None,
+            size: 0,
+        };
+        rotating.reopen()?;
+        Ok(rotating)
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn write(&mut self, line: &[u8]) -> Result<()> {
+        if self.file.is_none() {
+            self.reopen()?;
+        }
+        if self.size > 0 && self.size + line.len() as u64 > self.options.max_bytes {
+            self.rotate()?;
+        }
+        let file = self.file.as_mut().expect("Access log was just opened");
+        if let Err(e) = file.write_all(line) {
+            self.file = None;
+            return Err(e);
+        }
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let numbered = |index: u32| PathBuf::from(format!("{}.{}", self.path.display(), index));
+        self.file = None;
+        // Files past `keep` are left over from a larger setting
+        let mut index = self.options.keep.max(1);
+        while numbered(index).exists() {
+            fs::remove_file(numbered(index))?;
+            index += 1;
+        }
+        if self.options.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.options.keep).rev() {
+                let from = numbered(index);
+                if from.exists() {
+                    fs::rename(from, numbered(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, numbered(1))?;
+        }
+        self.reopen()
+    }
+}
+
+/// Formats as RFC 3339 in UTC with millisecond precision.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01, as in Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn formats_timestamps_as_rfc_3339() {
+        let at = |secs: u64, millis: u64| {
+            timestamp(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis))
+        };
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(951_782_400, 5), "2000-02-29T00:00:00.005Z");
+        assert_eq!(at(1_709_251_199, 999), "2024-02-29T23:59:59.999Z");
+        assert_eq!(at(4_102_444_800, 0), "2100-01-01T00:00:00.000Z");
+    }
+
+    /// A fresh log path in a scratch directory.
+    fn log_path(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("port_switch_access_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory.join("access.log")
+    }
+
+    fn numbered(path: &Path, index: u32) -> PathBuf {
+        PathBuf::from(format!("{}.{}", path.display(), index))
+    }
+
+    #[test]
+    fn rotates_past_the_size_limit_keeping_a_few_files() {
+        let path = log_path("rotate");
+        let options = AccessLogOptions {
+            max_bytes: 10,
+            keep: 2,
+        };
+        let mut log = Rotating::open(path.clone(), options).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            log.write(line.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(numbered(&path, 1)).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(numbered(&path, 2)).unwrap(), "second\n");
+        assert!(!numbered(&path, 3).exists());
+    }
+
+    #[test]
+    fn keeps_lines_together_and_none_when_asked() {
+        let path = log_path("keep_none");
+        let options = AccessLogOptions {
+            max_bytes: 4,
+            keep: 0,
+        };
+        let mut log = Rotating::open(path.clone(), options).unwrap();
+        // Longer than the limit on its own, still written whole
+        log.write(b"too long\n").unwrap();
+        log.write(b"next\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "next\n");
+        assert!(!numbered(&path, 1).exists());
+    }
+
+    #[test]
+    fn drops_files_past_a_lowered_keep() {
+        let path = log_path("lowered_keep");
+        for index in 1..=4 {
+            fs::write(numbered(&path, index), format!("old {index}\n")).unwrap();
+        }
+        let options = AccessLogOptions {
+            max_bytes: 4,
+            keep: 2,
+        };
+        let mut log = Rotating::open(path.clone(), options).unwrap();
+        log.write(b"first\n").unwrap();
+        log.write(b"second\n").unwrap();
+        assert_eq!(fs::read_to_string(numbered(&path, 1)).unwrap(), "first\n");
+        assert_eq!(fs::read_to_string(numbered(&path, 2)).unwrap(), "old 1\n");
+        assert!(!numbered(&path, 3).exists());
+        assert!(!numbered(&path, 4).exists());
+    }
+
+    #[test]
+    fn opens_the_log_again_after_it_was_removed() {
+        let path = log_path("reopen");
+        let mut log = Rotating::open(path.clone(), AccessLogOptions::default()).unwrap();
+        log.write(b"first\n").unwrap();
+        let directory = path.parent().unwrap();
+        fs::remove_dir_all(directory).unwrap();
+        // Gone along with its folder, the write fails and is retried by the next one
+        log.file = None;
+        assert!(log.write(b"lost\n").is_err());
+        fs::create_dir_all(directory).unwrap();
+        log.write(b"second\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second\n");
+    }
+
+    #[test]
+    fn appends_to_an_existing_log() {
+        let path = log_path("append");
+        fs::write(&path, "earlier\n").unwrap();
+        let mut log = Rotating::open(path.clone(), AccessLogOptions::default()).unwrap();
+        log.write(b"later\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "earlier\nlater\n");
+    }
+}
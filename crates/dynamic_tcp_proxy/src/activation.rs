@@ -0,0 +1,84 @@
+use std::io::Result;
+use std::net::TcpListener;
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// Sockets passed by systemd, taken over once and kept open for the whole process
+    /// so that turning the listener off and on again does not lose them.
+    static ref ACTIVATED: Vec<(String, TcpListener)> = take_activated();
+}
+
+/// Copies of the listening sockets systemd passed under `name`, in the order of the socket unit.
+pub(crate) fn listeners(name: &str) -> Result<Vec<TcpListener>> {
+    ACTIVATED
+        .iter()
+        .filter(|(activated, _)| activated == name)
+        .map(|(_, listener)| listener.try_clone())
+        .collect()
+}
+
+/// Ports of the listening sockets systemd passed under `name`, in the order of the socket unit.
+pub(crate) fn ports(name: &str) -> Vec<u16> {
+    ACTIVATED
+        .iter()
+        .filter(|(activated, _)| activated == name)
+        .filter_map(|(_, listener)| listener.local_addr().ok())
+        .map(|addr| addr.port())
+        .collect()
+}
+
+#[cfg(unix)]
+fn take_activated() -> Vec<(String, TcpListener)> {
+    use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+
+    use socket2::{Socket, Type};
+    use tracing::{info, warn};
+
+    /// First descriptor passed, after stdin, stdout and stderr
+    const LISTEN_FDS_START: RawFd = 3;
+
+    let var = |name| std::env::var(name).ok();
+    // The variables are inherited by children that did not get the sockets, which the pid tells
+    if var("LISTEN_PID").and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+        return Vec::new();
+    }
+    let Some(count) = var("LISTEN_FDS").and_then(|count| count.parse::<RawFd>().ok()) else {
+        return Vec::new();
+    };
+    let names = var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+
+    let mut activated = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        let name = names.next().unwrap_or("unknown").to_owned();
+        // Descriptors from LISTEN_FDS_START on are handed over to this process
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        let is_tcp = socket.r#type().is_ok_and(|kind| kind == Type::STREAM)
+            && socket
+                .local_addr()
+                .is_ok_and(|addr| addr.as_socket().is_some());
+        if !is_tcp {
+            warn!(fd, %name, "Ignoring activated socket that is not a TCP listener");
+            // Left open for whoever else may be expecting it
+            let _ = socket.into_raw_fd();
+            continue;
+        }
+        if let Err(e) = socket
+            .set_cloexec(true)
+            .and_then(|_| socket.set_nonblocking(true))
+        {
+            warn!(fd, %name, error = %e, "Cannot take over activated socket");
+            let _ = socket.into_raw_fd();
+            continue;
+        }
+        info!(fd, %name, "Using activated socket");
+        activated.push((name, socket.into()));
+    }
+    activated
+}
+
+#[cfg(not(unix))]
+fn take_activated() -> Vec<(String, TcpListener)> {
+    Vec::new()
+}
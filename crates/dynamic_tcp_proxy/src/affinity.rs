@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::{ForwardTarget, Route};
+
+/// Targets clients were sent to last, each with when that was.
+#[derive(Default)]
+pub(crate) struct Affinity(Mutex<HashMap<IpAddr, (ForwardTarget, Instant)>>);
+
+impl Affinity {
+    /// Target for a client, the one it got last time while that was less than `ttl` ago and
+    /// some route still points at it, `active` otherwise.
+    pub(crate) fn pick(
+        &self,
+        active: ForwardTarget,
+        routes: &[Route],
+        ip: IpAddr,
+        ttl: Duration,
+    ) -> ForwardTarget {
+        let mut pinned = self.0.lock().expect("Cannot lock affinity");
+        pinned.retain(|_, (_, used_at)| used_at.elapsed() < ttl);
+        let target = match pinned.remove(&ip) {
+            Some((target, _)) if routes.iter().any(|route| route.target == target) => target,
+            _ => active,
+        };
+        pinned.insert(ip, (target.clone(), Instant::now()));
+        target
+    }
+
+    /// Lets a client whose target failed to connect go to the active one next time.
+    pub(crate) fn unpin(&self, ip: IpAddr) {
+        self.0.lock().expect("Cannot lock affinity").remove(&ip);
+    }
+
+    /// Forgets every client, for a listener started over or set up differently.
+    pub(crate) fn clear(&self) {
+        self.0.lock().expect("Cannot lock affinity").clear();
+    }
+}
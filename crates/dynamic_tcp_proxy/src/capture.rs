@@ -0,0 +1,346 @@
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::mpsc::{channel, Sender};
+use std::task::{ready, Context, Poll};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::error;
+
+use crate::passthrough::passthrough_write;
+
+const LINKTYPE_RAW: u16 = 101;
+const MAX_SEGMENT: usize = 65_000;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// Handle to a pcapng file written by a background thread, the file is
+/// closed once every clone of the handle is dropped.
+#[derive(Clone)]
+pub(crate) struct Capture {
+    packets: Sender<(SystemTime, Vec<u8>)>,
+    /// Folder the file is written to
+    pub(crate) directory: PathBuf,
+}
+
+impl Capture {
+    pub(crate) fn create(directory: &Path, listen_port: u16) -> Result<(Self, PathBuf)> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = directory.join(format!("port_switch_{listen_port}_{started}.pcapng"));
+
+        let mut file = BufWriter::new(File::create(&path)?);
+        write_header(&mut file)?;
+
+        let (packet_tx, packet_rx) = channel::<(SystemTime, Vec<u8>)>();
+        thread::Builder::new()
+            .name("capture_writer".to_string())
+            .spawn(move || {
+                for (timestamp, packet) in packet_rx {
+                    if let Err(e) = write_packet(&mut file, timestamp, &packet) {
+                        error!(error = %e, "Failed to write capture");
+                        return;
+                    }
+                }
+                let _ = file.flush();
+            })?;
+
+        let capture = Self {
+            packets: packet_tx,
+            directory: directory.to_path_buf(),
+        };
+        Ok((capture, path))
+    }
+
+    fn send(&self, packet: Vec<u8>) {
+        let _ = self.packets.send((SystemTime::now(), packet));
+    }
+}
+
+/// Records the traffic of the client side stream as a synthesized TCP conversation
+/// between `client` and `server`: reads are client segments, writes server segments.
+pub(crate) struct Recorded<S> {
+    inner: S,
+    capture: Capture,
+    client: SocketAddr,
+    server: SocketAddr,
+    client_seq: u32,
+    server_seq: u32,
+}
+
+impl<S> Recorded<S> {
+    pub(crate) fn new(inner: S, capture: Capture, client: SocketAddr, server: SocketAddr) -> Self {
+        let mut recorded = Self {
+            inner,
+            capture,
+            client,
+            server,
+            client_seq: 0,
+            server_seq: 0,
+        };
+        recorded.segment(true, TCP_SYN, &[]);
+        recorded.segment(false, TCP_SYN | TCP_ACK, &[]);
+        recorded.segment(true, TCP_ACK, &[]);
+        recorded
+    }
+
+    fn segment(&mut self, from_client: bool, flags: u8, payload: &[u8]) {
+        let (src, dst, seq, ack) = if from_client {
+            (self.client, self.server, self.client_seq, self.server_seq)
+        } else {
+            (self.server, self.client, self.server_seq, self.client_seq)
+        };
+        self.capture
+            .send(build_packet(src, dst, seq, ack, flags, payload));
+
+        let mut advance = payload.len() as u32;
+        if flags & (TCP_SYN | TCP_FIN) != 0 {
+            advance += 1;
+        }
+        if from_client {
+            self.client_seq = self.client_seq.wrapping_add(advance);
+        } else {
+            self.server_seq = self.server_seq.wrapping_add(advance);
+        }
+    }
+
+    fn data(&mut self, from_client: bool, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT) {
+            self.segment(from_client, TCP_PSH | TCP_ACK, chunk);
+        }
+    }
+}
+
+impl<S> Drop for Recorded<S> {
+    fn drop(&mut self) {
+        self.segment(true, TCP_FIN | TCP_ACK, &[]);
+        self.segment(false, TCP_FIN | TCP_ACK, &[]);
+        self.segment(true, TCP_ACK, &[]);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorded<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.data(true, &buf.filled()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorded<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.data(false, &buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    passthrough_write!();
+}
+
+fn build_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    tcp.extend_from_slice(&[5 << 4, flags]);
+    tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+    tcp.extend_from_slice(&[0, 0, 0, 0]);
+    tcp.extend_from_slice(payload);
+
+    let tcp_len = tcp.len() as u32;
+    let mut packet = Vec::with_capacity(40 + tcp.len());
+    let checksum = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let total_len = (20 + tcp_len) as u16;
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&total_len.to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            packet.extend_from_slice(&src_ip.octets());
+            packet.extend_from_slice(&dst_ip.octets());
+            let header_checksum = checksum(&[&packet]);
+            packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+            let pseudo_len = (tcp_len as u16).to_be_bytes();
+            checksum(&[
+                &src_ip.octets(),
+                &dst_ip.octets(),
+                &[0, 6],
+                &pseudo_len,
+                &tcp,
+            ])
+        }
+        (src_ip, dst_ip) => {
+            let src_ip = to_v6(src_ip).octets();
+            let dst_ip = to_v6(dst_ip).octets();
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+            packet.extend_from_slice(&[6, 64]);
+            packet.extend_from_slice(&src_ip);
+            packet.extend_from_slice(&dst_ip);
+
+            checksum(&[
+                &src_ip,
+                &dst_ip,
+                &tcp_len.to_be_bytes(),
+                &[0, 0, 0, 6],
+                &tcp,
+            ])
+        }
+    };
+    tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&tcp);
+    packet
+}
+
+fn to_v6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    let mut odd: Option<u8> = None;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        match odd.take() {
+            Some(high) => sum += u32::from(u16::from_be_bytes([high, *byte])),
+            None => odd = Some(*byte),
+        }
+    }
+    if let Some(high) = odd {
+        sum += u32::from(u16::from_be_bytes([high, 0]));
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn write_header(file: &mut impl Write) -> Result<()> {
+    // Section header block
+    file.write_all(&0x0A0D_0D0Au32.to_le_bytes())?;
+    file.write_all(&28u32.to_le_bytes())?;
+    file.write_all(&0x1A2B_3C4Du32.to_le_bytes())?;
+    file.write_all(&1u16.to_le_bytes())?;
+    file.write_all(&0u16.to_le_bytes())?;
+    file.write_all(&(-1i64).to_le_bytes())?;
+    file.write_all(&28u32.to_le_bytes())?;
+
+    // Interface description block, raw IP with microsecond timestamps
+    file.write_all(&1u32.to_le_bytes())?;
+    file.write_all(&20u32.to_le_bytes())?;
+    file.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+    file.write_all(&0u16.to_le_bytes())?;
+    file.write_all(&0u32.to_le_bytes())?;
+    file.write_all(&20u32.to_le_bytes())?;
+    file.flush()
+}
+
+fn write_packet(file: &mut impl Write, timestamp: SystemTime, packet: &[u8]) -> Result<()> {
+    let micros = timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let padding = (4 - packet.len() % 4) % 4;
+    let block_len = (32 + packet.len() + padding) as u32;
+
+    // Enhanced packet block
+    file.write_all(&6u32.to_le_bytes())?;
+    file.write_all(&block_len.to_le_bytes())?;
+    file.write_all(&0u32.to_le_bytes())?;
+    file.write_all(&((micros >> 32) as u32).to_le_bytes())?;
+    file.write_all(&(micros as u32).to_le_bytes())?;
+    file.write_all(&(packet.len() as u32).to_le_bytes())?;
+    file.write_all(&(packet.len() as u32).to_le_bytes())?;
+    file.write_all(packet)?;
+    file.write_all(&[0; 3][..padding])?;
+    file.write_all(&block_len.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn checksums_as_in_rfc_1071() {
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(&[&data]), 0x220d);
+        // Parts are summed as one run of bytes, whatever their lengths
+        assert_eq!(checksum(&[&data[..3], &data[3..]]), 0x220d);
+        assert_eq!(checksum(&[&[0xab]]), !0xab00);
+    }
+
+    #[test]
+    fn builds_ipv4_segments() {
+        let src = "192.0.2.1:40000".parse().unwrap();
+        let dst = "192.0.2.2:80".parse().unwrap();
+        let packet = build_packet(src, dst, 1, 2, TCP_PSH | TCP_ACK, b"hi");
+        #[rustfmt::skip]
+        let expected = [
+            // IPv4 header
+            0x45, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0xb6, 0xca,
+            0xc0, 0x00, 0x02, 0x01, 0xc0, 0x00, 0x02, 0x02,
+            // TCP header and payload
+            0x9c, 0x40, 0x00, 0x50, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02,
+            0x50, 0x18, 0xff, 0xff, 0x26, 0xca, 0x00, 0x00, 0x68, 0x69,
+        ];
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn builds_ipv6_segments_with_valid_checksums() {
+        let src: SocketAddr = "[2001:db8::1]:40000".parse().unwrap();
+        let dst: SocketAddr = "[2001:db8::2]:80".parse().unwrap();
+        let packet = build_packet(src, dst, 7, 9, TCP_SYN, b"odd");
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(u16::from_be_bytes([packet[4], packet[5]]), 23);
+        // Summing a segment along with its pseudo header and checksum gives 0
+        let tcp = &packet[40..];
+        let tcp_len = (tcp.len() as u32).to_be_bytes();
+        assert_eq!(checksum(&[&packet[8..40], &tcp_len, &[0, 0, 0, 6], tcp]), 0);
+    }
+
+    #[test]
+    fn writes_padded_enhanced_packet_blocks() {
+        let mut written = Vec::new();
+        let timestamp = UNIX_EPOCH + Duration::from_secs(5_000) + Duration::from_micros(2);
+        write_packet(&mut written, timestamp, b"hello").unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            6, 0, 0, 0, 40, 0, 0, 0, 0, 0, 0, 0,
+            // 5_000_000_002 microseconds, high then low half
+            1, 0, 0, 0, 0x02, 0xf2, 0x05, 0x2a,
+            5, 0, 0, 0, 5, 0, 0, 0,
+            b'h', b'e', b'l', b'l', b'o', 0, 0, 0,
+            40, 0, 0, 0,
+        ];
+        assert_eq!(written, expected);
+    }
+}
@@ -1,12 +1,389 @@
+use std::fmt::{self, Display};
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 
+use crate::activation;
+
 #[derive(Default, Debug)]
-pub struct ProxyConfig(pub Option<(u16, ForwardTarget)>);
+pub struct ProxyConfig {
+    /// Turns the proxy off when missing
+    pub forwarding: Option<Forwarding>,
+    pub options: ListenerOptions,
+}
+
+#[derive(Debug, Clone)]
+pub struct Forwarding {
+    pub listen_port: u16,
+    pub target: ForwardTarget,
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ListenerOptions {
+    pub mode: ListenerMode,
+    /// Named targets that SOCKS5 and HTTP CONNECT destinations are mapped onto, first match wins.
+    /// In HTTP mode requests pick them by name.
+    pub routes: Vec<Route>,
+    /// Target names the "target unavailable" page lists, the routes to addresses when empty
+    pub listed_targets: Vec<String>,
+    pub unmatched: UnmatchedAction,
+    /// Username and password SOCKS5 clients must send, no authentication when not set
+    pub socks_credentials: Option<Credentials>,
+    /// Number of consecutive ports bound from the listening port, each forwarded to the
+    /// port at the same offset from the target port. 0 and 1 both bind a single port.
+    pub port_count: u16,
+    /// Listens on the sockets systemd passed under this name, see `LISTEN_FDNAMES`, instead of
+    /// binding the listening port. Each one forwards to the target port plus its position.
+    /// The listening port may then be 0, the port of the first socket naming files and metrics.
+    pub activated_socket: Option<String>,
+    /// Address the listening ports are bound on, loopback when not set. Other hosts can only
+    /// connect, and be matched by the access rules, once it is `0.0.0.0`, `::` or an interface's.
+    pub bind_address: Option<IpAddr>,
+    pub accept_proxy_protocol: bool,
+    /// Checked against the client address, the one in the PROXY header when accepted
+    pub access: AccessRules,
+    pub limits: ConnectionLimits,
+    pub network: Option<NetworkProfile>,
+    pub socket: SocketOptions,
+    /// Forwards plain TCP connections with `splice(2)` on Linux, keeping the bytes out of
+    /// user space. Connections that need to see them, for shaping, faults, idle timeouts,
+    /// captures or session recordings, are copied as usual, and spliced ones move over to
+    /// copying once they are inspected or any of those is turned on.
+    pub zero_copy: bool,
+    pub faults: FaultRules,
+    pub timeouts: Timeouts,
+    /// Keeps sending a client IP to the target it got, after switching to another one, for this
+    /// many seconds after its last connection. Clients move on once no route points at it.
+    pub affinity_secs: Option<u32>,
+    pub retry: RetryPolicy,
+    /// Target used once the retries against the active target are used up
+    pub fallback: Option<ForwardTarget>,
+    /// Serves the counters in the Prometheus text format at `/metrics` on this local port
+    pub metrics_port: Option<u16>,
+    pub record: bool,
+    pub record_sessions: bool,
+    /// Writes a line per closed connection to `port_switch_<port>_access.log` in the capture folder
+    pub access_log: Option<AccessLogOptions>,
+    /// Where capture files and session recordings go, the system temp directory when not set
+    pub capture_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Copy, Deserialize, Serialize)]
+pub enum ListenerMode {
+    /// Every connection goes to the active target
+    #[default]
+    Forward,
+    /// Clients pick their destination through SOCKS5 CONNECT
+    Socks5,
+    /// Clients pick their destination through HTTP CONNECT, as sent by `HTTPS_PROXY` aware tools
+    HttpConnect,
+    /// Plain HTTP forwarded to the active target, unless a request names another route through
+    /// the `X-Port-Switch` header, the `__ps` cookie or query parameter. Those requests are sent
+    /// with `Connection: close` so the next one on the connection is routed again.
+    Http,
+}
+
+/// Destinations matching `name`, either a bare host or `host:port`, are sent to `target`.
+#[derive(Debug, Default, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Route {
+    pub name: String,
+    pub target: ForwardTarget,
+}
+
+/// What happens to destinations no route matches.
+#[derive(Debug, Default, PartialEq, Clone, Copy, Deserialize, Serialize)]
+pub enum UnmatchedAction {
+    Direct,
+    #[default]
+    Reject,
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl ListenerOptions {
+    /// Picks the target for a destination requested by the client, `None` when it is rejected.
+    /// In forward and HTTP modes the destination was already picked and no route is looked up.
+    pub fn route(&self, destination: &ForwardTarget) -> Option<ForwardTarget> {
+        if matches!(self.mode, ListenerMode::Forward | ListenerMode::Http) {
+            return Some(destination.clone());
+        }
+        let host_port = format!("{}:{}", destination.domain, destination.port);
+        let route = self.routes.iter().find(|route| {
+            route.name.eq_ignore_ascii_case(&destination.domain)
+                || route.name.eq_ignore_ascii_case(&host_port)
+        });
+        match (route, self.unmatched) {
+            (Some(route), _) => Some(route.target.clone()),
+            (None, UnmatchedAction::Direct) => Some(destination.clone()),
+            (None, UnmatchedAction::Reject) => None,
+        }
+    }
+}
+
+/// Size based rotation of the access log.
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AccessLogOptions {
+    /// Size the log grows to before being moved aside to `.1`
+    pub max_bytes: u64,
+    /// Rotated files kept next to the log, the oldest being deleted
+    pub keep: u32,
+}
+
+impl Default for AccessLogOptions {
+    fn default() -> Self {
+        Self {
+            max_bytes: 10 * 1024 * 1024,
+            keep: 5,
+        }
+    }
+}
+
+/// Connection timeouts in seconds, `None` waits forever.
+#[derive(Debug, Default, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Timeouts {
+    /// Also bounds the client's handshake, from the PROXY header to its destination
+    /// or first HTTP request, which falls back to the idle timeout when not set
+    pub connect_secs: Option<u32>,
+    pub idle_secs: Option<u32>,
+    pub lifetime_secs: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub initial_backoff_ms: u32,
+    pub max_backoff_ms: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 0,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 2_000,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FaultRules {
+    /// Percentage of new connections reset right after being accepted
+    pub refuse: FaultRule<u8>,
+    pub reset_after_bytes: FaultRule<u64>,
+    pub reset_after_secs: FaultRule<u64>,
+    pub stall: bool,
+    /// Percentage of bytes flipped in either direction
+    pub corrupt: FaultRule<u8>,
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FaultRule<T> {
+    pub enabled: bool,
+    pub value: T,
+}
+
+impl Default for FaultRules {
+    /// All off, with values that do something once toggled on
+    fn default() -> Self {
+        fn off<T>(value: T) -> FaultRule<T> {
+            FaultRule {
+                enabled: false,
+                value,
+            }
+        }
+        Self {
+            refuse: off(50),
+            reset_after_bytes: off(64 * 1024),
+            reset_after_secs: off(30),
+            stall: false,
+            corrupt: off(1),
+        }
+    }
+}
+
+impl FaultRules {
+    pub fn any_enabled(&self) -> bool {
+        self.refuse.enabled
+            || self.reset_after_bytes.enabled
+            || self.reset_after_secs.enabled
+            || self.stall
+            || self.corrupt.enabled
+    }
+}
+
+/// Client networks allowed to connect, checked against the address each connection is accepted from.
+/// Deny entries win over allow entries and an empty allow list lets everyone else in.
+#[derive(Debug, Default, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AccessRules {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessRules {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConnectionLimits {
+    pub max_connections: Option<u32>,
+    pub max_per_ip: Option<u32>,
+    pub max_per_second: Option<u32>,
+    pub action: LimitAction,
+}
 
-#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+/// Options of the listening sockets and of both streams of every connection.
+/// Buffer sizes in bytes, `None` leaves the system default. The port reuse and backlog
+/// only change when the listener is started again.
+#[derive(Debug, Default, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SocketOptions {
+    /// Disables Nagle's algorithm so small writes of interactive protocols go out right away
+    pub nodelay: bool,
+    pub keepalive: Option<Keepalive>,
+    /// Lets another process bind the same ports, for example a second instance during a restart
+    pub reuse_port: bool,
+    /// Connections waiting to be accepted, 1024 when not set
+    pub backlog: Option<u32>,
+    pub send_buffer: Option<u32>,
+    pub recv_buffer: Option<u32>,
+}
+
+/// TCP keepalive probing in seconds, intervals and retries left to the system when not set.
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Keepalive {
+    /// Idle time before the first probe
+    pub time_secs: u32,
+    pub interval_secs: Option<u32>,
+    pub retries: Option<u32>,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            time_secs: 60,
+            interval_secs: None,
+            retries: None,
+        }
+    }
+}
+
+/// Link conditions to emulate, bandwidth in kilobits per second and latency added to each direction.
+#[derive(Debug, Default, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NetworkProfile {
+    pub name: String,
+    pub download_kbps: Option<u32>,
+    pub upload_kbps: Option<u32>,
+    pub latency_ms: u32,
+}
+
+impl NetworkProfile {
+    pub fn presets() -> Vec<NetworkProfile> {
+        let preset = |name: &str, download_kbps, upload_kbps, latency_ms| NetworkProfile {
+            name: name.to_owned(),
+            download_kbps: Some(download_kbps),
+            upload_kbps: Some(upload_kbps),
+            latency_ms,
+        };
+        vec![
+            preset("3G", 1_600, 750, 150),
+            preset("Slow Wi-Fi", 2_000, 1_000, 40),
+            preset("Satellite", 10_000, 1_000, 300),
+        ]
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Copy, Deserialize, Serialize)]
+pub enum LimitAction {
+    #[default]
+    Queue,
+    Refuse,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Deserialize, Serialize)]
 pub struct ForwardTarget {
     pub domain: String,
     pub port: u16,
+    #[serde(default)]
+    pub kind: TargetKind,
+}
+
+/// How connections to a target are served, `domain` and `port` only matter for addresses.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Deserialize, Serialize)]
+pub enum TargetKind {
+    #[default]
+    Address,
+    /// Answers every request with the same HTTP response
+    Respond(CannedResponse),
+    /// Answers with a "target unavailable" page listing the routes
+    Unavailable,
+    /// Serves the files of a local directory
+    Directory(DirectoryTarget),
+    /// Resets every connection right away
+    Reject,
+    /// Accepts connections and never answers
+    Blackhole,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DirectoryTarget {
+    pub root: PathBuf,
+    /// File served for requests to a directory
+    pub index: String,
+    /// Serves the root index file for paths matching no file, as single page apps expect
+    pub spa_fallback: bool,
+}
+
+impl Default for DirectoryTarget {
+    fn default() -> Self {
+        Self {
+            root: PathBuf::from("dist"),
+            index: "index.html".to_owned(),
+            spa_fallback: true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CannedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Default for CannedResponse {
+    fn default() -> Self {
+        Self {
+            status: 200,
+            headers: vec![("Content-Type".to_owned(), "text/plain".to_owned())],
+            body: String::new(),
+        }
+    }
 }
 
 impl Default for ForwardTarget {
@@ -14,6 +391,7 @@ impl Default for ForwardTarget {
         Self {
             domain: "localhost".to_owned(),
             port: 0,
+            kind: TargetKind::Address,
         }
     }
 }
@@ -22,34 +400,123 @@ impl ForwardTarget {
     pub fn is_external(&self) -> bool {
         self.domain != "localhost"
     }
+
+    /// Whether the proxy answers connections itself instead of connecting anywhere.
+    pub fn is_local(&self) -> bool {
+        self.kind != TargetKind::Address
+    }
+
+    /// The same host with the port moved up by `offset`, `None` past the last port.
+    pub fn offset(&self, offset: u16) -> Option<ForwardTarget> {
+        Some(ForwardTarget {
+            port: self.port.checked_add(offset)?,
+            ..self.clone()
+        })
+    }
+}
+
+impl Display for ForwardTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            TargetKind::Address => write!(f, "{}:{}", self.domain, self.port),
+            TargetKind::Respond(response) => write!(f, "canned {} response", response.status),
+            TargetKind::Unavailable => f.write_str("unavailable page"),
+            TargetKind::Directory(directory) => write!(f, "{}", directory.root.display()),
+            TargetKind::Reject => f.write_str("reject"),
+            TargetKind::Blackhole => f.write_str("blackhole"),
+        }
+    }
 }
 
 impl ProxyConfig {
+    /// Listens on `listen_port` and forwards to `target`.
+    pub fn forward(listen_port: u16, target: ForwardTarget, options: ListenerOptions) -> Self {
+        Self {
+            forwarding: Some(Forwarding {
+                listen_port,
+                target,
+            }),
+            options,
+        }
+    }
+
     pub fn is_off(&self) -> bool {
-        self.0.is_none()
+        self.forwarding.is_none()
     }
 
     pub fn is_on(&self) -> bool {
-        self.0.is_some()
+        self.forwarding.is_some()
     }
 
     pub fn listen_port(&self) -> Option<u16> {
-        if let Some((listen_port, _)) = self.0 {
-            return Some(listen_port);
-        }
-        None
+        self.forwarding
+            .as_ref()
+            .map(|forwarding| forwarding.listen_port)
     }
+
     pub fn forward_port(&self) -> Option<ForwardTarget> {
-        if let Some((_, forward_port)) = &self.0 {
-            return Some(forward_port.clone());
-        }
-        None
+        self.forwarding
+            .as_ref()
+            .map(|forwarding| forwarding.target.clone())
+    }
+
+    pub fn options(&self) -> &ListenerOptions {
+        &self.options
     }
 
     pub fn validate(&self) -> Result<(), String> {
-        match (self.forward_port(), self.listen_port()) {
-            (Some(fp), Some(lp)) if fp.domain == "localhost" && fp.port == lp => {
-                Err("Cannot forward to listening port".to_owned())
+        let Some(lp) = self.listen_port() else {
+            return Ok(());
+        };
+        let limits = &self.options().limits;
+        if [limits.max_connections, limits.max_per_ip].contains(&Some(0)) {
+            return Err("Connection limits must allow at least one connection".to_owned());
+        }
+        let count = self.options().port_count.max(1);
+        // The ports of activated sockets are only known once they are taken over
+        let activated = self
+            .options()
+            .activated_socket
+            .as_deref()
+            .map(activation::ports);
+        if activated.is_none() && lp.checked_add(count - 1).is_none() {
+            return Err("Listening range goes past the last port".to_owned());
+        }
+        // Each listening port forwards to the same offset from the target port
+        let span = match &activated {
+            Some(ports) => {
+                u16::try_from(ports.len()).map_err(|_| "Too many activated sockets".to_owned())?
+            }
+            None => count,
+        };
+        let targets = self
+            .forward_port()
+            .into_iter()
+            .chain(self.options().fallback.clone());
+        for target in targets.filter(|target| !target.is_local()) {
+            if target.offset(span.saturating_sub(1)).is_none() {
+                return Err("Target range goes past the last port".to_owned());
+            }
+        }
+        let overlaps = |target: &ForwardTarget| {
+            if target.is_local() || target.domain != "localhost" {
+                return false;
+            }
+            match &activated {
+                // Socket n forwards to the target port plus n
+                Some(ports) => ports.iter().any(|port| {
+                    port.checked_sub(target.port)
+                        .is_some_and(|offset| usize::from(offset) < ports.len())
+                }),
+                // Ranges of the same length overlap when their starts are less than a length apart
+                None => target.port.abs_diff(lp) < count,
+            }
+        };
+
+        match (self.forward_port(), &self.options().fallback) {
+            (Some(fp), _) if overlaps(&fp) => Err("Cannot forward to listening port".to_owned()),
+            (_, Some(fallback)) if overlaps(fallback) => {
+                Err("Cannot fall back to listening port".to_owned())
             }
             _ => Ok(()),
         }
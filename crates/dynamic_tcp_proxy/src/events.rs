@@ -0,0 +1,143 @@
+use std::fmt::{self, Display};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::config::ForwardTarget;
+use std::sync::mpsc::SyncSender;
+
+pub(crate) const EVENT_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProxyEvent {
+    ConnectionRejected {
+        peer: SocketAddr,
+        reason: RejectReason,
+    },
+    FaultInjected {
+        peer: SocketAddr,
+        fault: FaultKind,
+    },
+    TimedOut {
+        peer: SocketAddr,
+        timeout: TimeoutKind,
+    },
+    UpstreamFailed {
+        peer: SocketAddr,
+        error: String,
+    },
+    FallbackUsed {
+        peer: SocketAddr,
+        target: ForwardTarget,
+    },
+    CaptureStarted(PathBuf),
+    CaptureFailed(String),
+    SessionRecordingStarted(PathBuf),
+    AccessLogStarted(PathBuf),
+    AccessLogFailed(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RejectReason {
+    MaxConnections,
+    MaxConnectionsPerIp,
+    RateLimited,
+    NotAllowed,
+    NoRoute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultKind {
+    Refused,
+    ResetAfterBytes,
+    ResetAfterTime,
+}
+
+impl Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fault = match self {
+            FaultKind::Refused => "refused",
+            FaultKind::ResetAfterBytes => "reset after byte limit",
+            FaultKind::ResetAfterTime => "reset after time limit",
+        };
+        f.write_str(fault)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeoutKind {
+    /// The client did not send its PROXY header, proxy handshake or first request in time
+    Handshake,
+    Connect,
+    Idle,
+    Lifetime,
+}
+
+impl Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let timeout = match self {
+            TimeoutKind::Handshake => "handshake timeout",
+            TimeoutKind::Connect => "connect timeout",
+            TimeoutKind::Idle => "idle timeout",
+            TimeoutKind::Lifetime => "maximum lifetime",
+        };
+        f.write_str(timeout)
+    }
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            RejectReason::MaxConnections => "too many connections",
+            RejectReason::MaxConnectionsPerIp => "too many connections from client",
+            RejectReason::RateLimited => "connection rate exceeded",
+            RejectReason::NotAllowed => "client not allowed",
+            RejectReason::NoRoute => "no route for destination",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl Display for ProxyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyEvent::ConnectionRejected { peer, reason } => {
+                write!(f, "Rejected {}: {}", peer, reason)
+            }
+            ProxyEvent::FaultInjected { peer, fault } => {
+                write!(f, "Fault injected for {}: {}", peer, fault)
+            }
+            ProxyEvent::TimedOut { peer, timeout } => {
+                write!(f, "Closed {}: {}", peer, timeout)
+            }
+            ProxyEvent::UpstreamFailed { peer, error } => {
+                write!(f, "Cannot reach target for {}: {}", peer, error)
+            }
+            ProxyEvent::FallbackUsed { peer, target } => {
+                write!(f, "Sent {} to fallback {}", peer, target)
+            }
+            ProxyEvent::CaptureStarted(path) => write!(f, "Recording to {}", path.display()),
+            ProxyEvent::CaptureFailed(err) => write!(f, "Cannot start recording: {}", err),
+            ProxyEvent::SessionRecordingStarted(dir) => {
+                write!(f, "Recording sessions in {}", dir.display())
+            }
+            ProxyEvent::AccessLogStarted(path) => {
+                write!(f, "Logging connections to {}", path.display())
+            }
+            ProxyEvent::AccessLogFailed(err) => write!(f, "Cannot open access log: {}", err),
+        }
+    }
+}
+
+/// Hands events over to the `DynamicProxy` owner, dropping them when nobody drains the queue.
+#[derive(Clone)]
+pub(crate) struct EventSink(SyncSender<ProxyEvent>);
+
+impl EventSink {
+    pub(crate) fn new(tx: SyncSender<ProxyEvent>) -> Self {
+        Self(tx)
+    }
+
+    pub(crate) fn emit(&self, event: ProxyEvent) {
+        let _ = self.0.try_send(event);
+    }
+}
@@ -0,0 +1,147 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use rand::Rng;
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{Instant, Sleep};
+
+use crate::config::FaultRules;
+use crate::events::{EventSink, FaultKind, ProxyEvent};
+use crate::passthrough::passthrough_write;
+use crate::settings::Settings;
+
+const STALL_RECHECK: Duration = Duration::from_millis(100);
+
+pub(crate) fn should_refuse(rules: &FaultRules) -> bool {
+    rules.refuse.enabled && rand::thread_rng().gen_range(0..100) < rules.refuse.value
+}
+
+/// Client connection that applies the listener's fault rules as they are toggled,
+/// so that already open connections are affected too.
+pub(crate) struct Faulty {
+    inner: TcpStream,
+    peer: SocketAddr,
+    events: EventSink,
+    settings: Arc<Settings>,
+    opened_at: Instant,
+    transferred: u64,
+    timer: Pin<Box<Sleep>>,
+}
+
+impl Faulty {
+    pub(crate) fn new(
+        inner: TcpStream,
+        peer: SocketAddr,
+        events: EventSink,
+        settings: Arc<Settings>,
+    ) -> Self {
+        Self {
+            inner,
+            peer,
+            events,
+            settings,
+            opened_at: Instant::now(),
+            transferred: 0,
+            timer: Box::pin(tokio::time::sleep(Duration::ZERO)),
+        }
+    }
+
+    /// Checks the reset rules, and the stall rule when `reading`, registering a wake up for
+    /// whichever fires next.
+    fn poll_faults(&mut self, cx: &mut Context<'_>, reading: bool) -> Poll<Result<FaultRules>> {
+        let rules = self.settings.faults();
+        let now = Instant::now();
+        let mut wake_at = None;
+
+        if rules.reset_after_bytes.enabled && self.transferred >= rules.reset_after_bytes.value {
+            return Poll::Ready(Err(self.reset(FaultKind::ResetAfterBytes)));
+        }
+        if rules.reset_after_secs.enabled {
+            let deadline = self.opened_at + Duration::from_secs(rules.reset_after_secs.value);
+            if deadline <= now {
+                return Poll::Ready(Err(self.reset(FaultKind::ResetAfterTime)));
+            }
+            wake_at = Some(deadline);
+        }
+        let stall = rules.stall && reading;
+        if stall {
+            let recheck = now + STALL_RECHECK;
+            wake_at = Some(wake_at.map_or(recheck, |at: Instant| at.min(recheck)));
+        }
+
+        if let Some(wake_at) = wake_at {
+            self.timer.as_mut().reset(wake_at);
+            let _ = self.timer.as_mut().poll(cx);
+        }
+        if stall {
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(rules))
+        }
+    }
+
+    fn reset(&mut self, fault: FaultKind) -> Error {
+        // Zero linger makes the close send an RST instead of a FIN
+        let _ = SockRef::from(&self.inner).set_linger(Some(Duration::ZERO));
+        self.events.emit(ProxyEvent::FaultInjected {
+            peer: self.peer,
+            fault,
+        });
+        Error::new(ErrorKind::ConnectionReset, "Fault injected reset")
+    }
+}
+
+fn corrupt(data: &mut [u8], percent: u8) {
+    let mut rng = rand::thread_rng();
+    for byte in data {
+        if rng.gen_range(0..100) < percent {
+            *byte ^= rng.gen_range(1..=u8::MAX);
+        }
+    }
+}
+
+impl AsyncRead for Faulty {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let rules = ready!(this.poll_faults(cx, true))?;
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let read = &mut buf.filled_mut()[filled..];
+        this.transferred += read.len() as u64;
+        if rules.corrupt.enabled {
+            corrupt(read, rules.corrupt.value);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Faulty {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let rules = ready!(this.poll_faults(cx, false))?;
+
+        let written = if rules.corrupt.enabled {
+            let mut data = buf.to_vec();
+            corrupt(&mut data, rules.corrupt.value);
+            ready!(Pin::new(&mut this.inner).poll_write(cx, &data))?
+        } else {
+            ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?
+        };
+        this.transferred += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    passthrough_write!();
+}
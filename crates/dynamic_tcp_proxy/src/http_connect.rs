@@ -0,0 +1,156 @@
+use std::io::{Error, ErrorKind};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::ForwardTarget;
+
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+/// Status lines sent back to the client once the destination was dealt with.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Reply {
+    Established,
+    BadRequest,
+    Forbidden,
+    MethodNotAllowed,
+    BadGateway,
+    GatewayTimeout,
+}
+
+/// Reads the `CONNECT host:port` request head, returning the requested destination.
+/// No response has been sent yet, that is up to the caller through `reply`.
+pub(crate) async fn read_request<S>(stream: &mut S) -> Result<ForwardTarget, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Read byte by byte so that the tunnelled bytes following the head stay in the socket
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_LEN {
+            return Err(invalid("CONNECT request head too long"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let (method, authority) = (request_line.next(), request_line.next());
+    if method != Some("CONNECT") {
+        reply(stream, Reply::MethodNotAllowed).await?;
+        return Err(invalid("Only CONNECT requests are supported"));
+    }
+
+    match parse_authority(authority.unwrap_or_default()) {
+        Some(target) => Ok(target),
+        None => {
+            reply(stream, Reply::BadRequest).await?;
+            Err(invalid("Invalid CONNECT authority"))
+        }
+    }
+}
+
+fn parse_authority(authority: &str) -> Option<ForwardTarget> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    Some(ForwardTarget {
+        domain: host.to_owned(),
+        port: port.parse().ok()?,
+        ..Default::default()
+    })
+}
+
+pub(crate) async fn reply<S>(stream: &mut S, reply: Reply) -> Result<(), Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    let status = match reply {
+        Reply::Established => "200 Connection Established",
+        Reply::BadRequest => "400 Bad Request",
+        Reply::Forbidden => "403 Forbidden",
+        Reply::MethodNotAllowed => "405 Method Not Allowed",
+        Reply::BadGateway => "502 Bad Gateway",
+        Reply::GatewayTimeout => "504 Gateway Timeout",
+    };
+    let response = match reply {
+        Reply::Established => format!("HTTP/1.1 {status}\r\n\r\n"),
+        _ => format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+    };
+    stream.write_all(response.as_bytes()).await
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    /// Reads the request `sent` by a client, returning the destination, what the client was
+    /// answered and what stayed in the stream.
+    async fn run(sent: &[u8]) -> (Result<ForwardTarget, Error>, Vec<u8>, Vec<u8>) {
+        let (mut client, mut server) = duplex(16 * 1024);
+        client.write_all(sent).await.unwrap();
+        client.shutdown().await.unwrap();
+        let target = read_request(&mut server).await;
+        let mut left = Vec::new();
+        server.read_to_end(&mut left).await.unwrap();
+        drop(server);
+        let mut answered = Vec::new();
+        client.read_to_end(&mut answered).await.unwrap();
+        (target, answered, left)
+    }
+
+    #[tokio::test]
+    async fn reads_the_authority_and_leaves_the_tunnel() {
+        let sent = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n\x16\x03";
+        let (target, answered, left) = run(sent).await;
+        let target = target.unwrap();
+        assert_eq!((target.domain.as_str(), target.port), ("example.com", 443));
+        assert!(answered.is_empty());
+        assert_eq!(left, b"\x16\x03");
+
+        let target = run(b"CONNECT [2001:db8::1]:8443 HTTP/1.1\r\n\r\n")
+            .await
+            .0
+            .unwrap();
+        assert_eq!((target.domain.as_str(), target.port), ("2001:db8::1", 8443));
+    }
+
+    #[tokio::test]
+    async fn refuses_other_methods() {
+        let (target, answered, _) = run(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await;
+        assert_eq!(target.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(answered.starts_with(b"HTTP/1.1 405 "));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_authorities() {
+        for authority in [
+            "example.com",
+            ":443",
+            "example.com:http",
+            "example.com:65536",
+        ] {
+            let sent = format!("CONNECT {authority} HTTP/1.1\r\n\r\n");
+            let (target, answered, _) = run(sent.as_bytes()).await;
+            assert!(target.is_err(), "{authority}");
+            assert!(answered.starts_with(b"HTTP/1.1 400 "), "{authority}");
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_the_request_head() {
+        let mut sent = b"CONNECT example.com:443 HTTP/1.1\r\nX-Padding: ".to_vec();
+        sent.resize(MAX_HEAD_LEN + 1, b'a');
+        assert!(run(&sent).await.0.is_err());
+
+        let (target, _, _) = run(b"CONNECT example.com:443 HTTP/1.1\r\n").await;
+        assert_eq!(target.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
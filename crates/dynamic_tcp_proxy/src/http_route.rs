@@ -0,0 +1,326 @@
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::passthrough::passthrough_write;
+
+const MAX_HEAD_LEN: usize = 16 * 1024;
+const HEADER: &str = "x-port-switch";
+const COOKIE: &str = "__ps";
+const QUERY: &str = "__ps";
+
+/// Target picked by a request.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Selection {
+    /// No preference, the listener's active target
+    Default,
+    /// A target named by the header or the cookie
+    Named(String),
+    /// The query parameter asking to remember a target, answered with a redirect
+    /// back to `location` setting the cookie. An empty name forgets it.
+    Remember { name: String, location: String },
+}
+
+/// Reads up to the end of the request head, returning everything read so far
+/// so it can be handed over to the target.
+pub(crate) async fn read_head<S>(stream: &mut S) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = Vec::new();
+    let mut buf = [0; 4096];
+    while !contains_head_end(&head) {
+        if head.len() >= MAX_HEAD_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "Request head too long"));
+        }
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+    Ok(head)
+}
+
+fn contains_head_end(data: &[u8]) -> bool {
+    data.windows(4).any(|window| window == b"\r\n\r\n")
+}
+
+pub(crate) fn select(head: &[u8]) -> Selection {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.lines();
+    let path = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .unwrap_or_default();
+
+    if let Some((location, name)) = take_query_param(path) {
+        return Selection::Remember { name, location };
+    }
+
+    let mut cookie = None;
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case(HEADER) && !value.is_empty() {
+            return Selection::Named(value.to_owned());
+        }
+        if name.eq_ignore_ascii_case("cookie") {
+            cookie = cookie.or_else(|| {
+                value
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(key, value)| *key == COOKIE && !value.is_empty())
+                    .map(|(_, value)| percent_decode(value))
+            });
+        }
+    }
+    cookie.map_or(Selection::Default, Selection::Named)
+}
+
+/// Asks for the connection to close once the request in `head` is answered, so that the
+/// requests after it get routed again. Upgrades are left alone, the connection carries
+/// another protocol after them.
+pub(crate) fn close_after(head: Vec<u8>) -> Vec<u8> {
+    let Some(end) = head.windows(4).position(|window| window == b"\r\n\r\n") else {
+        return head;
+    };
+    let Ok(lines) = std::str::from_utf8(&head[..end]) else {
+        return head;
+    };
+
+    let mut kept = Vec::new();
+    for (index, line) in lines.split("\r\n").enumerate() {
+        let connection = line
+            .split_once(':')
+            .filter(|(name, _)| index > 0 && name.trim().eq_ignore_ascii_case("connection"));
+        match connection {
+            Some((_, value)) if value.to_ascii_lowercase().contains("upgrade") => return head,
+            Some(_) => {}
+            None => kept.push(line),
+        }
+    }
+    kept.push("Connection: close");
+    let mut rewritten = kept.join("\r\n").into_bytes();
+    rewritten.extend_from_slice(&head[end..]);
+    rewritten
+}
+
+/// Splits the `__ps` parameter off `path`, returning the path without it and its decoded value.
+fn take_query_param(path: &str) -> Option<(String, String)> {
+    let (base, query) = path.split_once('?')?;
+    let mut value = None;
+    let rest: Vec<&str> = query
+        .split('&')
+        .filter(|pair| {
+            let (key, pair_value) = pair.split_once('=').unwrap_or((pair, ""));
+            if key != QUERY {
+                return true;
+            }
+            value = Some(percent_decode(&pair_value.replace('+', " ")));
+            false
+        })
+        .collect();
+
+    // `//host` and `/\host` are taken by browsers as another site, anything but a local path
+    // could send the client off this one
+    let base = match base.as_bytes() {
+        [b'/', b'/' | b'\\', ..] => "/",
+        [b'/', ..] => base,
+        _ => "/",
+    };
+    let location = match rest.is_empty() {
+        true => base.to_owned(),
+        false => format!("{}?{}", base, rest.join("&")),
+    };
+    Some((location, value?))
+}
+
+pub(crate) fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = bytes
+            .get(index + 1..index + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Sends the client back to `location` with the cookie set, or cleared when `name` is empty.
+pub(crate) async fn redirect<S>(stream: &mut S, name: &str, location: &str) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let cookie = match name.is_empty() {
+        true => format!("{COOKIE}=; Path=/; Max-Age=0"),
+        false => format!("{COOKIE}={}; Path=/; HttpOnly", percent_encode(name)),
+    };
+    let response = format!(
+        "HTTP/1.1 302 Found\r\nLocation: {location}\r\nSet-Cookie: {cookie}\r\n\
+         Content-Length: 0\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Replays the bytes already read off the client before reading from it again.
+pub(crate) struct Prefixed<S> {
+    inner: S,
+    prefix: Vec<u8>,
+    position: usize,
+}
+
+impl<S> Prefixed<S> {
+    pub(crate) fn new(inner: S, prefix: Vec<u8>) -> Self {
+        Self {
+            inner,
+            prefix,
+            position: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let remaining = &this.prefix[this.position..];
+        if remaining.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let len = remaining.len().min(buf.remaining());
+        buf.put_slice(&remaining[..len]);
+        this.position += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    passthrough_write!(poll_write);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(lines: &str) -> Vec<u8> {
+        format!("{lines}\r\n\r\n").into_bytes()
+    }
+
+    #[test]
+    fn selects_by_header_then_cookie() {
+        let head = request("GET / HTTP/1.1\r\nX-Port-Switch: beta\r\nCookie: __ps=alpha");
+        assert_eq!(select(&head), Selection::Named("beta".to_owned()));
+        let head = request("GET / HTTP/1.1\r\nx-port-switch:  beta ");
+        assert_eq!(select(&head), Selection::Named("beta".to_owned()));
+        let head = request("GET / HTTP/1.1\r\nCookie: theme=dark; __ps=my%20app");
+        assert_eq!(select(&head), Selection::Named("my app".to_owned()));
+        let head = request("GET / HTTP/1.1\r\nX-Port-Switch: \r\nCookie: __ps=");
+        assert_eq!(select(&head), Selection::Default);
+    }
+
+    #[test]
+    fn headers_stop_at_the_end_of_the_head() {
+        let mut head = request("POST / HTTP/1.1\r\nHost: a");
+        head.extend(b"X-Port-Switch: body");
+        assert_eq!(select(&head), Selection::Default);
+    }
+
+    #[test]
+    fn query_parameter_remembers_and_forgets() {
+        let head = request("GET /app?a=1&__ps=beta+2&b=2 HTTP/1.1");
+        assert_eq!(
+            select(&head),
+            Selection::Remember {
+                name: "beta 2".to_owned(),
+                location: "/app?a=1&b=2".to_owned(),
+            }
+        );
+        let head = request("GET /?__ps= HTTP/1.1\r\nCookie: __ps=beta");
+        assert_eq!(
+            select(&head),
+            Selection::Remember {
+                name: String::new(),
+                location: "/".to_owned(),
+            }
+        );
+        let head = request("GET /?a=1 HTTP/1.1");
+        assert_eq!(select(&head), Selection::Default);
+    }
+
+    #[test]
+    fn query_parameter_redirects_only_to_local_paths() {
+        let location = |path: &str| match select(&request(&format!("GET {path} HTTP/1.1"))) {
+            Selection::Remember { location, .. } => location,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(location("//evil.com/?__ps=x"), "/");
+        assert_eq!(location("/\\evil.com/?__ps=x&a=1"), "/?a=1");
+        assert_eq!(location("http://evil.com/?__ps=x"), "/");
+        assert_eq!(location("/app//page?__ps=x"), "/app//page");
+    }
+
+    #[test]
+    fn percent_decode_leaves_invalid_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc"), "a b/c");
+        assert_eq!(percent_decode("%2e%2E"), "..");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert!(!percent_decode("%+1").contains('\u{1}'));
+        assert_eq!(percent_decode("%ff"), "\u{FFFD}");
+        assert_eq!(percent_decode(&percent_encode("a b/é")), "a b/é");
+    }
+
+    #[test]
+    fn close_after_replaces_keep_alive() {
+        let head = b"GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\n\r\nbody".to_vec();
+        assert_eq!(
+            close_after(head),
+            b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\nbody"
+        );
+        let head = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n".to_vec();
+        assert_eq!(
+            close_after(head),
+            b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn close_after_leaves_upgrades() {
+        let head = b"GET /ws HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+        assert_eq!(close_after(head.to_vec()), head);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::io::Result;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryIter, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Instant, SystemTime};
+
+use lazy_static::lazy_static;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+
+use crate::config::ForwardTarget;
+use crate::passthrough::passthrough_write;
+use crate::session::{Chunk, Direction};
+
+const INSPECT_BUFFER: usize = 256;
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<HashMap<u64, Arc<Tap>>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub target: ForwardTarget,
+    pub opened_at: SystemTime,
+}
+
+/// Receives the chunks of an inspected connection, inspection stops when dropped.
+pub struct Inspector(Receiver<Chunk>);
+
+impl Inspector {
+    pub fn chunks(&self) -> TryIter<'_, Chunk> {
+        self.0.try_iter()
+    }
+}
+
+struct Tap {
+    info: ConnectionInfo,
+    started: Instant,
+    // Checked on every read and write so uninspected connections only pay for an atomic load
+    inspected: AtomicBool,
+    /// Woken once an inspector was attached
+    attached: Notify,
+    sender: Mutex<Option<SyncSender<Chunk>>>,
+}
+
+impl Tap {
+    fn send(&self, direction: Direction, data: &[u8]) {
+        if data.is_empty() || !self.inspected.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut sender = self.sender.lock().expect("Cannot lock inspector");
+        let Some(tx) = sender.as_ref() else {
+            return;
+        };
+        let chunk = Chunk {
+            offset: self.started.elapsed(),
+            direction,
+            data: data.to_vec(),
+        };
+        // A full buffer drops the chunk rather than holding up the connection
+        if let Err(TrySendError::Disconnected(_)) = tx.try_send(chunk) {
+            *sender = None;
+            self.inspected.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Identifies a connection, in the logs as well as for inspection.
+pub(crate) fn next_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub(crate) fn connections() -> Vec<ConnectionInfo> {
+    let connections = CONNECTIONS.lock().expect("Cannot lock connections");
+    let mut infos: Vec<ConnectionInfo> = connections.values().map(|tap| tap.info.clone()).collect();
+    infos.sort_by_key(|info| info.id);
+    infos
+}
+
+pub(crate) fn inspect(id: u64) -> Option<Inspector> {
+    let connections = CONNECTIONS.lock().expect("Cannot lock connections");
+    let tap = connections.get(&id)?;
+    let (tx, rx) = sync_channel(INSPECT_BUFFER);
+    *tap.sender.lock().expect("Cannot lock inspector") = Some(tx);
+    tap.inspected.store(true, Ordering::Relaxed);
+    tap.attached.notify_one();
+    Some(Inspector(rx))
+}
+
+/// Lists the connection among the open ones until dropped.
+pub(crate) struct Registered(Arc<Tap>);
+
+impl Registered {
+    pub(crate) fn new(id: u64, peer: SocketAddr, target: ForwardTarget) -> Self {
+        let tap = Arc::new(Tap {
+            info: ConnectionInfo {
+                id,
+                peer,
+                target,
+                opened_at: SystemTime::now(),
+            },
+            started: Instant::now(),
+            inspected: AtomicBool::new(false),
+            attached: Notify::new(),
+            sender: Mutex::new(None),
+        });
+        CONNECTIONS
+            .lock()
+            .expect("Cannot lock connections")
+            .insert(id, tap.clone());
+        Self(tap)
+    }
+
+    pub(crate) fn is_inspected(&self) -> bool {
+        self.0.inspected.load(Ordering::Relaxed)
+    }
+
+    /// Resolves once the connection is being inspected.
+    pub(crate) async fn inspected(&self) {
+        while !self.is_inspected() {
+            self.0.attached.notified().await;
+        }
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        CONNECTIONS
+            .lock()
+            .expect("Cannot lock connections")
+            .remove(&self.0.info.id);
+    }
+}
+
+/// Hands the bytes of a registered connection to its inspector, if any.
+pub(crate) struct Inspected<S> {
+    inner: S,
+    registered: Registered,
+}
+
+impl<S> Inspected<S> {
+    pub(crate) fn new(inner: S, registered: Registered) -> Self {
+        Self { inner, registered }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Inspected<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.registered
+            .0
+            .send(Direction::ClientToServer, &buf.filled()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Inspected<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.registered
+            .0
+            .send(Direction::ServerToClient, &buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    passthrough_write!();
+}
@@ -1,61 +1,123 @@
+mod access_log;
+mod activation;
+mod affinity;
+mod capture;
 mod config;
+mod events;
+mod faults;
+mod http_connect;
+mod http_route;
+mod inspector;
+mod limits;
+mod local;
+mod metrics;
+mod passthrough;
 mod proxy_handler;
+mod proxy_protocol;
+mod session;
+mod settings;
+mod shaping;
+mod sockets;
+mod socks;
+#[cfg(target_os = "linux")]
+mod splice;
+mod static_files;
+mod stats;
+mod timeouts;
+mod upstream;
 
 use std::io::Error;
-use std::sync::mpsc::{channel, Receiver as StdReceiver, Sender as StdSender};
-use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
+use std::sync::mpsc::{
+    channel, sync_channel, Receiver as StdReceiver, Sender as StdSender, TryIter,
+};
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc::{self, Sender};
 
-use lazy_static::lazy_static;
+use events::{EventSink, EVENT_BUFFER};
 use proxy_handler::create_proxy;
-
-pub use config::{ForwardTarget, ProxyConfig};
+use settings::Settings;
+
+pub use config::{
+    AccessLogOptions, AccessRules, CannedResponse, ConnectionLimits, Credentials, DirectoryTarget,
+    FaultRule, FaultRules, ForwardTarget, Forwarding, Keepalive, LimitAction, ListenerMode,
+    ListenerOptions, NetworkProfile, ProxyConfig, RetryPolicy, Route, SocketOptions, TargetKind,
+    Timeouts, UnmatchedAction,
+};
+pub use events::{FaultKind, ProxyEvent, RejectReason, TimeoutKind};
+pub use inspector::{ConnectionInfo, Inspector};
+pub use ipnet::IpNet;
+pub use session::{replay, Chunk, Direction, Session};
+pub use stats::ProxyStats;
 use tokio::task::JoinHandle as TokioJoinHandle;
 
-lazy_static! {
-    static ref TARGET_PORT: Arc<Mutex<Option<ForwardTarget>>> =
-        Arc::new(Mutex::new(Default::default()));
-}
-
-fn get_target() -> ForwardTarget {
-    let read_guard = TARGET_PORT.lock().expect("Cannot lock target port mutex");
-    read_guard.clone().unwrap()
-}
-
-fn set_target(target: ForwardTarget) {
-    let mut write_guard = TARGET_PORT.lock().expect("Cannot lock target port mutex");
-    *write_guard = Some(target);
-}
-
-pub struct DynamicProxy(StdSender<ProxyConfig>);
+pub struct DynamicProxy(
+    StdSender<ProxyConfig>,
+    StdReceiver<ProxyEvent>,
+    Arc<Settings>,
+);
 
 impl DynamicProxy {
     pub fn initiate() -> Result<(Self, JoinHandle<()>), Error> {
         let (update_tx, update_rx): (StdSender<ProxyConfig>, StdReceiver<ProxyConfig>) = channel();
+        let (event_tx, event_rx) = sync_channel(EVENT_BUFFER);
+        let events = EventSink::new(event_tx);
 
+        let settings = Arc::new(Settings::new());
+
+        let observed = settings.clone();
         let handle = thread::Builder::new()
             .name("dynamic_proxy".to_string())
-            .spawn(move || initiate_update_observer(update_rx))?;
-        Ok((Self(update_tx), handle))
+            .spawn(move || initiate_update_observer(update_rx, events, observed))?;
+        Ok((Self(update_tx, event_rx, settings), handle))
     }
 
+    #[allow(clippy::result_large_err)]
     pub fn update(
         &self,
         config: ProxyConfig,
     ) -> Result<(), std::sync::mpsc::SendError<ProxyConfig>> {
         self.0.send(config)
     }
+
+    pub fn events(&self) -> TryIter<'_, ProxyEvent> {
+        self.1.try_iter()
+    }
+
+    /// Addresses the listener is bound to, with the ports picked for a listening port of 0.
+    pub fn listening(&self) -> Vec<SocketAddr> {
+        self.2.listening()
+    }
+
+    pub fn stats(&self) -> ProxyStats {
+        stats::STATS.snapshot()
+    }
+
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        inspector::connections()
+    }
+
+    pub fn inspect(&self, connection_id: u64) -> Option<Inspector> {
+        inspector::inspect(connection_id)
+    }
 }
 
-fn initiate_update_observer(update_rx: StdReceiver<ProxyConfig>) {
+fn initiate_update_observer(
+    update_rx: StdReceiver<ProxyConfig>,
+    events: EventSink,
+    settings: Arc<Settings>,
+) {
     let mut running_proxy_thread: Option<TokioJoinHandle<()>> = None;
     let mut proxy_kill_tx: Option<Sender<()>> = None;
 
+    let mut metrics_server: Option<(u16, TokioJoinHandle<()>)> = None;
+
     let runtime = Runtime::new().unwrap();
     while let Ok(config) = update_rx.recv() {
+        update_metrics(&runtime, config.options().metrics_port, &mut metrics_server);
         if config.is_off() && running_proxy_thread.is_some() {
             let curr_shudown_tx = proxy_kill_tx.clone();
             runtime.block_on(async move {
@@ -65,19 +127,43 @@ fn initiate_update_observer(update_rx: StdReceiver<ProxyConfig>) {
             });
 
             running_proxy_thread = None;
+            metrics::stopped();
+            settings.affinity.clear();
+            settings.set_listening(Vec::new());
+            settings.stop_recording();
         } else if config.is_on() {
             let forward_port = config
                 .forward_port()
                 .expect("Listening port not set before starting server");
-            set_target(forward_port);
+            let options = config.options();
+            let listen_port = match (config.listen_port(), &options.activated_socket) {
+                (Some(0), Some(name)) => activation::ports(name).first().copied().unwrap_or(0),
+                (listen_port, _) => {
+                    listen_port.expect("Listening port not set before starting server")
+                }
+            };
+            // SOCKS5 and HTTP CONNECT listeners have no target of their own
+            match options.mode {
+                ListenerMode::Forward | ListenerMode::Http => {
+                    metrics::switched(listen_port, options, &forward_port)
+                }
+                ListenerMode::Socks5 | ListenerMode::HttpConnect => metrics::stopped(),
+            }
+            // Clients are pinned to targets of the listener as it was set up
+            if *options != settings.options() {
+                settings.affinity.clear();
+            }
+            settings.apply(forward_port, options, listen_port, &events);
 
             if running_proxy_thread.is_none() {
-                let listen_port = config
-                    .listen_port()
-                    .expect("Listening port not set before starting server");
-
                 let (new_proxy_kill_tx, new_proxy_kill_rx) = mpsc::channel::<()>(1);
-                let handle = create_proxy(&runtime, listen_port, new_proxy_kill_rx);
+                let handle = create_proxy(
+                    &runtime,
+                    listen_port,
+                    new_proxy_kill_rx,
+                    events.clone(),
+                    settings.clone(),
+                );
                 running_proxy_thread = Some(handle);
                 proxy_kill_tx = Some(new_proxy_kill_tx);
             }
@@ -94,3 +180,18 @@ fn initiate_update_observer(update_rx: StdReceiver<ProxyConfig>) {
         });
     }
 }
+
+/// Starts, moves or stops the metrics endpoint to match `port`.
+fn update_metrics(
+    runtime: &Runtime,
+    port: Option<u16>,
+    server: &mut Option<(u16, TokioJoinHandle<()>)>,
+) {
+    if server.as_ref().map(|(port, _)| *port) == port {
+        return;
+    }
+    if let Some((_, handle)) = server.take() {
+        handle.abort();
+    }
+    *server = port.map(|port| (port, runtime.spawn(metrics::serve(port))));
+}
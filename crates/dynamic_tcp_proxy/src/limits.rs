@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::config::{ConnectionLimits, LimitAction};
+use crate::events::RejectReason;
+
+pub(crate) struct ConnectionLimiter {
+    limits: ConnectionLimits,
+    active: Option<Arc<Semaphore>>,
+    per_ip: Mutex<HashMap<IpAddr, u32>>,
+    per_ip_released: Notify,
+    rate: Option<Mutex<TokenBucket>>,
+}
+
+/// Keeps a connection counted against the limits until dropped.
+pub(crate) struct Admission {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionLimiter {
+    pub(crate) fn new(limits: ConnectionLimits) -> Arc<Self> {
+        let active = limits
+            .max_connections
+            .map(|max| Arc::new(Semaphore::new(max as usize)));
+        let rate = limits
+            .max_per_second
+            .map(|rate| Mutex::new(TokenBucket::new(rate)));
+
+        Arc::new(Self {
+            limits,
+            active,
+            per_ip: Default::default(),
+            per_ip_released: Notify::new(),
+            rate,
+        })
+    }
+
+    /// In queue mode, holds off the next `accept` until the connection and rate
+    /// limits leave room, leaving excess clients in the listen backlog.
+    pub(crate) async fn ready(&self) -> Option<OwnedSemaphorePermit> {
+        if self.limits.action != LimitAction::Queue {
+            return None;
+        }
+
+        if let Some(rate) = &self.rate {
+            loop {
+                let wait = rate.lock().expect("Cannot lock rate limiter").take();
+                match wait {
+                    Ok(()) => break,
+                    Err(wait) => tokio::time::sleep(wait).await,
+                }
+            }
+        }
+
+        match &self.active {
+            Some(active) => active.clone().acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+
+    pub(crate) async fn admit(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> Result<Admission, RejectReason> {
+        let permit = match self.limits.action {
+            LimitAction::Queue => {
+                self.wait_for_ip(ip).await;
+                permit
+            }
+            LimitAction::Refuse => {
+                let permit = match &self.active {
+                    Some(active) => Some(
+                        active
+                            .clone()
+                            .try_acquire_owned()
+                            .map_err(|_| RejectReason::MaxConnections)?,
+                    ),
+                    None => None,
+                };
+                if !self.try_count_ip(ip) {
+                    return Err(RejectReason::MaxConnectionsPerIp);
+                }
+                // Taken last so connections refused for another reason leave the token
+                if let Some(rate) = &self.rate {
+                    let taken = rate.lock().expect("Cannot lock rate limiter").take();
+                    if taken.is_err() {
+                        self.uncount_ip(ip);
+                        return Err(RejectReason::RateLimited);
+                    }
+                }
+                permit
+            }
+        };
+
+        Ok(Admission {
+            limiter: self.clone(),
+            ip,
+            _permit: permit,
+        })
+    }
+
+    async fn wait_for_ip(&self, ip: IpAddr) {
+        loop {
+            let released = self.per_ip_released.notified();
+            if self.try_count_ip(ip) {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    fn uncount_ip(&self, ip: IpAddr) {
+        let mut per_ip = self.per_ip.lock().expect("Cannot lock per ip counter");
+        if let Some(count) = per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&ip);
+            }
+        }
+        self.per_ip_released.notify_waiters();
+    }
+
+    fn try_count_ip(&self, ip: IpAddr) -> bool {
+        let mut per_ip = self.per_ip.lock().expect("Cannot lock per ip counter");
+        let count = per_ip.entry(ip).or_default();
+        match self.limits.max_per_ip {
+            Some(max) if *count >= max => false,
+            _ => {
+                *count += 1;
+                true
+            }
+        }
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.limiter.uncount_ip(self.ip);
+    }
+}
+
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_second: u32) -> Self {
+        let rate = per_second.max(1) as f64;
+        Self {
+            rate,
+            tokens: rate,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or returns how long until one becomes available.
+    fn take(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Moves the last refill back as if `elapsed` had passed since.
+    fn wait(bucket: &mut TokenBucket, elapsed: Duration) {
+        bucket.refilled_at -= elapsed;
+    }
+
+    #[test]
+    fn token_bucket_allows_a_burst_of_its_rate() {
+        let mut bucket = TokenBucket::new(4);
+        for _ in 0..4 {
+            assert_eq!(bucket.take(), Ok(()));
+        }
+        let until = bucket.take().unwrap_err();
+        assert!(until > Duration::ZERO && until <= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn token_bucket_refills_up_to_its_rate() {
+        let mut bucket = TokenBucket::new(4);
+        while bucket.take().is_ok() {}
+        wait(&mut bucket, Duration::from_millis(500));
+        assert_eq!(bucket.take(), Ok(()));
+        assert_eq!(bucket.take(), Ok(()));
+        assert!(bucket.take().is_err());
+
+        wait(&mut bucket, Duration::from_secs(60));
+        for _ in 0..4 {
+            assert_eq!(bucket.take(), Ok(()));
+        }
+        assert!(bucket.take().is_err());
+    }
+
+    #[test]
+    fn token_bucket_rate_is_at_least_one() {
+        let mut bucket = TokenBucket::new(0);
+        assert_eq!(bucket.take(), Ok(()));
+        let until = bucket.take().unwrap_err();
+        assert!(until <= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn refused_connections_leave_their_rate_token() {
+        let limiter = ConnectionLimiter::new(ConnectionLimits {
+            max_connections: Some(1),
+            max_per_second: Some(2),
+            action: LimitAction::Refuse,
+            ..Default::default()
+        });
+        let ip = "192.0.2.1".parse().unwrap();
+        let admitted = limiter.admit(ip, None).await.unwrap();
+        for _ in 0..3 {
+            assert!(matches!(
+                limiter.admit(ip, None).await,
+                Err(RejectReason::MaxConnections)
+            ));
+        }
+        drop(admitted);
+        assert!(limiter.admit(ip, None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn refuses_past_the_limit_per_ip() {
+        let limiter = ConnectionLimiter::new(ConnectionLimits {
+            max_per_ip: Some(1),
+            action: LimitAction::Refuse,
+            ..Default::default()
+        });
+        let (a, b) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let admitted = limiter.admit(a, None).await.unwrap();
+        assert!(matches!(
+            limiter.admit(a, None).await,
+            Err(RejectReason::MaxConnectionsPerIp)
+        ));
+        assert!(limiter.admit(b, None).await.is_ok());
+        drop(admitted);
+        assert!(limiter.admit(a, None).await.is_ok());
+    }
+}
@@ -0,0 +1,211 @@
+use std::io::Result;
+use std::time::Duration;
+
+use socket2::SockRef;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::config::{CannedResponse, ForwardTarget, ListenerOptions, TargetKind};
+use crate::http_route;
+use crate::static_files;
+use crate::stats::Counted;
+use crate::timeouts::{within, IdleTimeout};
+
+/// Answers a connection to a target the proxy serves itself. `head` holds whatever
+/// was already read off the client, the rest of it being read by `handshake`. Fails
+/// once the client idled for the listener's idle timeout.
+pub(crate) async fn serve(
+    mut inbound: Counted<TcpStream>,
+    head: Vec<u8>,
+    handshake: Option<Instant>,
+    target: &ForwardTarget,
+    options: &ListenerOptions,
+) -> Result<()> {
+    if target.kind == TargetKind::Reject {
+        let _ = SockRef::from(inbound.get_ref()).set_linger(Some(Duration::ZERO));
+        return Ok(());
+    }
+    let Some(idle_secs) = options.timeouts.idle_secs else {
+        answer(&mut inbound, head, handshake, target, options).await;
+        return Ok(());
+    };
+    let mut inbound = IdleTimeout::new(inbound, Duration::from_secs(idle_secs.into()));
+    let activity = inbound.activity();
+    tokio::select! {
+        _ = answer(&mut inbound, head, handshake, target, options) => Ok(()),
+        e = activity.expired() => Err(e),
+    }
+}
+
+/// Plays a local target other than `Reject`, which needs the socket to reset it, on any stream.
+pub(crate) async fn answer<S>(
+    inbound: &mut S,
+    head: Vec<u8>,
+    handshake: Option<Instant>,
+    target: &ForwardTarget,
+    options: &ListenerOptions,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if target.kind == TargetKind::Blackhole {
+        // Swallow whatever the client sends until it gives up
+        let _ = tokio::io::copy(inbound, &mut tokio::io::sink()).await;
+        return;
+    }
+
+    let head = match head.is_empty() {
+        true => within(handshake, http_route::read_head(inbound)).await,
+        false => Ok(head),
+    };
+    let Ok(head) = head else {
+        return;
+    };
+    let head_only = head.starts_with(b"HEAD ");
+
+    let response = match &target.kind {
+        TargetKind::Address | TargetKind::Reject | TargetKind::Blackhole => return,
+        TargetKind::Respond(response) => Response::from(response.clone()),
+        TargetKind::Unavailable => unavailable_page(options).into(),
+        TargetKind::Directory(directory) => static_files::respond(directory, &head).await,
+    };
+    if let Err(e) = respond(inbound, response, head_only).await {
+        warn!(error = %e, "Failed to answer");
+    }
+}
+
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Body,
+}
+
+pub(crate) enum Body {
+    Bytes(Vec<u8>),
+    /// Streamed from the file, which is `len` bytes long
+    File(File, u64),
+}
+
+impl Body {
+    fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File(_, len) => *len,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<CannedResponse> for Response {
+    fn from(response: CannedResponse) -> Self {
+        Self {
+            status: response.status,
+            headers: response.headers,
+            body: response.body.into_bytes().into(),
+        }
+    }
+}
+
+pub(crate) async fn respond(
+    inbound: &mut (impl AsyncWrite + Unpin),
+    response: Response,
+    head_only: bool,
+) -> Result<()> {
+    let mut message = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason_phrase(response.status)
+    );
+    for (name, value) in &response.headers {
+        // Framed below, whatever the configured headers say
+        if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Connection") {
+            continue;
+        }
+        if [name, value].iter().any(|text| text.contains(['\r', '\n'])) {
+            warn!(%name, "Skipping header with a line break");
+            continue;
+        }
+        message.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    message.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    ));
+
+    inbound.write_all(message.as_bytes()).await?;
+    match response.body {
+        _ if head_only => {}
+        Body::Bytes(bytes) => inbound.write_all(&bytes).await?,
+        Body::File(file, len) => {
+            tokio::io::copy(&mut file.take(len), inbound).await?;
+        }
+    }
+    inbound.shutdown().await
+}
+
+fn unavailable_page(options: &ListenerOptions) -> CannedResponse {
+    let names: Vec<&String> = match options.listed_targets.is_empty() {
+        true => options
+            .routes
+            .iter()
+            .filter(|route| !route.target.is_local())
+            .map(|route| &route.name)
+            .collect(),
+        false => options.listed_targets.iter().collect(),
+    };
+    let routes: String = names
+        .iter()
+        .map(|name| format!("<li>{}</li>", escape(name)))
+        .collect();
+    let body = format!(
+        "<!DOCTYPE html>\n<html><head><title>Target unavailable</title></head><body>\n\
+         <h1>Target unavailable</h1>\n\
+         <p>No target is serving this port right now. The configured ports are:</p>\n\
+         <ul>{}</ul>\n</body></html>\n",
+        routes
+    );
+    CannedResponse {
+        status: 503,
+        headers: vec![(
+            "Content-Type".to_owned(),
+            "text/html; charset=utf-8".to_owned(),
+        )],
+        body,
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
@@ -0,0 +1,372 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info, warn};
+
+use crate::config::{ForwardTarget, ListenerMode, ListenerOptions};
+use crate::http_route;
+use crate::local::{self, Response};
+
+/// Upper bounds in seconds of the connect latency buckets.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+/// Series are labeled by the port a connection came in on and the target's name.
+type Labels = (u16, String);
+
+#[derive(Default)]
+struct Registry {
+    listeners: BTreeMap<u16, ListenerMetrics>,
+    targets: BTreeMap<Labels, TargetMetrics>,
+    switches: BTreeMap<Labels, u64>,
+    active_target: Option<Labels>,
+}
+
+#[derive(Default)]
+struct ListenerMetrics {
+    accepted: u64,
+    denied: u64,
+    rejected: u64,
+}
+
+#[derive(Default)]
+struct TargetMetrics {
+    connections: u64,
+    open: u64,
+    from_client: u64,
+    from_server: u64,
+    upstream_errors: u64,
+    connect_seconds: Histogram,
+}
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+fn with_listener(listener: u16, update: impl FnOnce(&mut ListenerMetrics)) {
+    let mut registry = REGISTRY.lock().expect("Cannot lock metrics");
+    update(registry.listeners.entry(listener).or_default())
+}
+
+fn with_target<T>(labels: Labels, update: impl FnOnce(&mut TargetMetrics) -> T) -> T {
+    let mut registry = REGISTRY.lock().expect("Cannot lock metrics");
+    update(registry.targets.entry(labels).or_default())
+}
+
+/// Name of the route pointing at `target`, its address when there is none. Routes are
+/// searched from the end, where the configured ports come after the overrides.
+pub(crate) fn target_name(options: &ListenerOptions, target: &ForwardTarget) -> String {
+    options
+        .routes
+        .iter()
+        .rev()
+        .find(|route| route.target == *target)
+        .map_or_else(|| target.to_string(), |route| route.name.clone())
+}
+
+/// Series label of `target`. The destinations SOCKS5 and HTTP CONNECT clients reach without
+/// a route could be any host, they share one label rather than adding a series each.
+fn target_label(options: &ListenerOptions, target: &ForwardTarget) -> String {
+    let direct = matches!(
+        options.mode,
+        ListenerMode::Socks5 | ListenerMode::HttpConnect
+    ) && !options.routes.iter().any(|route| route.target == *target)
+        && options.fallback.as_ref() != Some(target);
+    match direct {
+        true => "direct".to_owned(),
+        false => target_name(options, target),
+    }
+}
+
+pub(crate) fn accepted(listener: u16) {
+    with_listener(listener, |metrics| metrics.accepted += 1);
+}
+
+pub(crate) fn denied(listener: u16) {
+    with_listener(listener, |metrics| metrics.denied += 1);
+}
+
+pub(crate) fn rejected(listener: u16) {
+    with_listener(listener, |metrics| metrics.rejected += 1);
+}
+
+pub(crate) fn connected(
+    listener: u16,
+    options: &ListenerOptions,
+    target: &ForwardTarget,
+    took: Duration,
+) {
+    with_target((listener, target_label(options, target)), |metrics| {
+        metrics.connect_seconds.observe(took.as_secs_f64())
+    });
+}
+
+pub(crate) fn upstream_failed(listener: u16, options: &ListenerOptions, target: &ForwardTarget) {
+    with_target((listener, target_label(options, target)), |metrics| {
+        metrics.upstream_errors += 1
+    });
+}
+
+pub(crate) fn switched(listener: u16, options: &ListenerOptions, target: &ForwardTarget) {
+    let mut registry = REGISTRY.lock().expect("Cannot lock metrics");
+    let labels = (listener, target_label(options, target));
+    if registry.active_target.as_ref() == Some(&labels) {
+        return;
+    }
+    *registry.switches.entry(labels.clone()).or_default() += 1;
+    registry.active_target = Some(labels);
+}
+
+pub(crate) fn stopped() {
+    REGISTRY.lock().expect("Cannot lock metrics").active_target = None;
+}
+
+/// A connection handed to a target, counted as open until dropped.
+pub(crate) struct Open(Labels);
+
+impl Open {
+    pub(crate) fn new(listener: u16, options: &ListenerOptions, target: &ForwardTarget) -> Self {
+        let labels = (listener, target_label(options, target));
+        with_target(labels.clone(), |metrics| {
+            metrics.connections += 1;
+            metrics.open += 1;
+        });
+        Self(labels)
+    }
+
+    pub(crate) fn transferred(&self, from_client: u64, from_server: u64) {
+        with_target(self.0.clone(), |metrics| {
+            metrics.from_client += from_client;
+            metrics.from_server += from_server;
+        });
+    }
+}
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        with_target(self.0.clone(), |metrics| metrics.open -= 1);
+    }
+}
+
+/// Answers `GET /metrics` on `port` with the counters in the Prometheus text format.
+pub(crate) async fn serve(port: u16) {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(%addr, error = %e, "Failed to serve metrics");
+            return;
+        }
+    };
+    info!(%addr, "Serving metrics");
+    while let Ok((stream, peer)) = listener.accept().await {
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream).await {
+                warn!(%peer, error = %e, "Failed to answer metrics request");
+            }
+        });
+    }
+}
+
+async fn answer(mut stream: TcpStream) -> std::io::Result<()> {
+    let head = http_route::read_head(&mut stream).await?;
+    let mut request_line = head.split(|byte| *byte == b' ');
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+    let response = match (method, path) {
+        (b"GET" | b"HEAD", b"/metrics") => Response {
+            status: 200,
+            headers: vec![(
+                "Content-Type".to_owned(),
+                "text/plain; version=0.0.4; charset=utf-8".to_owned(),
+            )],
+            body: render().into_bytes().into(),
+        },
+        _ => Response {
+            status: 404,
+            headers: Vec::new(),
+            body: Vec::new().into(),
+        },
+    };
+    local::respond(&mut stream, response, method == b"HEAD").await
+}
+
+fn render() -> String {
+    let registry = REGISTRY.lock().expect("Cannot lock metrics");
+    let mut out = String::new();
+
+    type Count = fn(&ListenerMetrics) -> u64;
+    let per_listener: [(&str, &str, Count); 3] = [
+        ("accepted_total", "Connections accepted", |m| m.accepted),
+        (
+            "denied_total",
+            "Connections closed as the client is not allowed",
+            |m| m.denied,
+        ),
+        (
+            "rejected_total",
+            "Connections closed by the connection limits",
+            |m| m.rejected,
+        ),
+    ];
+    for (name, help, value) in per_listener {
+        header(&mut out, name, "counter", help);
+        for (listener, metrics) in &registry.listeners {
+            let labels = format!("{{listener=\"{}\"}}", listener);
+            sample(&mut out, name, labels, value(metrics));
+        }
+    }
+
+    type Value = fn(&TargetMetrics) -> u64;
+    let per_target: [(&str, &str, &str, Value); 3] = [
+        (
+            "connections_total",
+            "counter",
+            "Connections handed to a target",
+            |m| m.connections,
+        ),
+        (
+            "connections_open",
+            "gauge",
+            "Connections open to a target",
+            |m| m.open,
+        ),
+        (
+            "upstream_errors_total",
+            "counter",
+            "Failed connections to a target",
+            |m| m.upstream_errors,
+        ),
+    ];
+    for (name, kind, help, value) in per_target {
+        header(&mut out, name, kind, help);
+        for (labels, metrics) in &registry.targets {
+            sample(&mut out, name, label_set(labels, ""), value(metrics));
+        }
+    }
+
+    header(
+        &mut out,
+        "bytes_total",
+        "counter",
+        "Bytes forwarded by closed connections",
+    );
+    for (labels, metrics) in &registry.targets {
+        let client = label_set(labels, ",direction=\"client\"");
+        sample(&mut out, "bytes_total", client, metrics.from_client);
+        let server = label_set(labels, ",direction=\"server\"");
+        sample(&mut out, "bytes_total", server, metrics.from_server);
+    }
+
+    let name = "connect_duration_seconds";
+    header(
+        &mut out,
+        name,
+        "histogram",
+        "Time to connect to a target, retries included",
+    );
+    for (labels, metrics) in &registry.targets {
+        let histogram = &metrics.connect_seconds;
+        if histogram.count == 0 {
+            continue;
+        }
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(histogram.counts) {
+            cumulative += count;
+            let le = label_set(labels, &format!(",le=\"{}\"", bound));
+            sample(&mut out, &format!("{}_bucket", name), le, cumulative);
+        }
+        let le = label_set(labels, ",le=\"+Inf\"");
+        sample(&mut out, &format!("{}_bucket", name), le, histogram.count);
+        sample(
+            &mut out,
+            &format!("{}_sum", name),
+            label_set(labels, ""),
+            histogram.sum,
+        );
+        sample(
+            &mut out,
+            &format!("{}_count", name),
+            label_set(labels, ""),
+            histogram.count,
+        );
+    }
+
+    let name = "target_switches_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Times the listener was switched to a target",
+    );
+    for (labels, switches) in &registry.switches {
+        sample(&mut out, name, label_set(labels, ""), switches);
+    }
+    let name = "active_target";
+    header(&mut out, name, "gauge", "Target the listener forwards to");
+    if let Some(labels) = &registry.active_target {
+        sample(&mut out, name, label_set(labels, ""), 1);
+    }
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP port_switch_{} {}.", name, help);
+    let _ = writeln!(out, "# TYPE port_switch_{} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: String, value: impl Display) {
+    let _ = writeln!(out, "port_switch_{}{} {}", name, labels, value);
+}
+
+fn label_set((listener, target): &Labels, extra: &str) -> String {
+    let target = target
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!(
+        "{{listener=\"{}\",target=\"{}\"{}}}",
+        listener, target, extra
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_admissions_by_listener() {
+        accepted(8080);
+        accepted(8080);
+        denied(8081);
+        rejected(8080);
+        let rendered = render();
+        assert!(rendered.contains("port_switch_accepted_total{listener=\"8080\"} 2\n"));
+        assert!(rendered.contains("port_switch_accepted_total{listener=\"8081\"} 0\n"));
+        assert!(rendered.contains("port_switch_denied_total{listener=\"8081\"} 1\n"));
+        assert!(rendered.contains("port_switch_rejected_total{listener=\"8080\"} 1\n"));
+    }
+}
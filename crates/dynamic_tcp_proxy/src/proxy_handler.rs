@@ -1,61 +1,647 @@
-use std::net::{SocketAddr, ToSocketAddrs};
+use socket2::SockRef;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::{watch, OwnedSemaphorePermit};
 use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 
-use crate::config::ForwardTarget;
+use crate::access_log::{self, AccessLog};
+use crate::activation;
+use crate::capture::Recorded;
+use crate::config::{ForwardTarget, ListenerMode, ListenerOptions, NetworkProfile};
+use crate::events::{EventSink, FaultKind, ProxyEvent, RejectReason, TimeoutKind};
+use crate::faults::{self, Faulty};
+use crate::http_connect;
+use crate::http_route::{self, Prefixed, Selection};
+use crate::inspector::{self, Inspected, Registered};
+use crate::limits::ConnectionLimiter;
+use crate::local;
+use crate::metrics;
+use crate::proxy_protocol;
+use crate::session::SessionTap;
+use crate::settings::Settings;
+use crate::shaping::Shaped;
+use crate::sockets;
+use crate::socks;
+#[cfg(target_os = "linux")]
+use crate::splice::{self, Spliced};
+use crate::stats::{Counted, Transferred, STATS};
+use crate::timeouts::{within, Activity, IdleTimeout};
+use crate::upstream;
+
+/// Pause after a failed accept before trying again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
 pub(super) fn create_proxy(
     runtime: &Runtime,
     listen_port: u16,
     kill_rx: Receiver<()>,
+    events: EventSink,
+    settings: Arc<Settings>,
 ) -> JoinHandle<()> {
-    let addr = SocketAddr::from(([127, 0, 0, 1], listen_port));
-
     runtime.spawn(async move {
-        let listener = TcpListener::bind(addr).await.unwrap();
-
-        let kill_signal = create_kill_signal(kill_rx);
-        let mut kill_signal = std::pin::pin!(kill_signal);
-
-        let ForwardTarget{ domain, port } = super::get_target();
-
-        let target_socket = format!("{domain}:{port}");
-
-        let mut forward_addr = target_socket.to_socket_addrs().expect("Invalid domain")
-            .next()
-            .expect("No address found");
-        forward_addr.set_port(port);
-
-        loop {
-            tokio::select! {
-                Ok((mut inbound, _addr)) = listener.accept() => {
-                    tokio::spawn(async move {
-                        match TcpStream::connect(forward_addr).await {
-                            Ok(mut outbound) => {
-                                let (from_client, from_server) = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await.unwrap();
-
-                                println!(
-                                    "client wrote {} bytes and received {} bytes",
-                                    from_client, from_server
-                                );
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to connect to forward address: {}", e);
-                            }
-                        }
-                    });
-                },
+        let options = settings.options();
+        let bound = listen(listen_port, &options);
+        let limiter = ConnectionLimiter::new(options.limits);
+        let addrs = bound
+            .iter()
+            .filter_map(|(_, listener)| listener.local_addr().ok())
+            .collect();
+        settings.set_listening(addrs);
+        STATS.reset();
 
-                _ = &mut kill_signal => {
-                    eprintln!("Graceful shutdown signal received");
-                    break;
+        let (stop_tx, stop_rx) = watch::channel(());
+        let listeners: Vec<_> = bound
+            .into_iter()
+            .map(|(offset, listener)| {
+                tokio::spawn(serve(
+                    listener,
+                    offset,
+                    limiter.clone(),
+                    events.clone(),
+                    settings.clone(),
+                    stop_rx.clone(),
+                ))
+            })
+            .collect();
+
+        create_kill_signal(kill_rx).await;
+        info!(listen_port, "Graceful shutdown signal received");
+        let _ = stop_tx.send(());
+        for listener in listeners {
+            let _ = listener.await;
+        }
+        info!(listen_port, "Listener stopped");
+    })
+}
+
+/// Listeners paired with their offset from the listening port, taken over from systemd
+/// when an activated socket is configured and bound otherwise.
+fn listen(listen_port: u16, options: &ListenerOptions) -> Vec<(u16, TcpListener)> {
+    let mut listeners = Vec::new();
+    if let Some(name) = &options.activated_socket {
+        let activated = match activation::listeners(name) {
+            Ok(activated) => activated,
+            Err(e) => {
+                error!(%name, error = %e, "Failed to use activated socket");
+                return listeners;
+            }
+        };
+        if activated.is_empty() {
+            error!(%name, "No activated socket with this name");
+        }
+        for (offset, listener) in (0..).zip(activated) {
+            match TcpListener::from_std(listener) {
+                Ok(listener) => {
+                    if let Ok(addr) = listener.local_addr() {
+                        info!(%addr, %name, "Listening on activated socket");
+                    }
+                    listeners.push((offset, listener));
                 }
+                Err(e) => error!(%name, error = %e, "Failed to use activated socket"),
             }
         }
-        println!("All connections Closed");
-    })
+        return listeners;
+    }
+
+    for offset in 0..options.port_count.max(1) {
+        // Port 0 lets the system pick each port of the range
+        let port = match listen_port {
+            0 => 0,
+            _ => match listen_port.checked_add(offset) {
+                Some(port) => port,
+                None => break,
+            },
+        };
+        let ip = options.bind_address.unwrap_or(Ipv4Addr::LOCALHOST.into());
+        let addr = SocketAddr::new(ip, port);
+        match sockets::bind(addr, &options.socket) {
+            Ok(listener) => {
+                info!(%addr, "Listening");
+                listeners.push((offset, listener));
+            }
+            Err(e) => error!(%addr, error = %e, "Failed to listen"),
+        }
+    }
+    listeners
+}
+
+/// Accepts connections on one port of the listening range, `offset` being its
+/// distance from the first port.
+async fn serve(
+    listener: TcpListener,
+    offset: u16,
+    limiter: Arc<ConnectionLimiter>,
+    events: EventSink,
+    settings: Arc<Settings>,
+    mut stop_rx: watch::Receiver<()>,
+) {
+    let port = listener.local_addr().map_or(0, |addr| addr.port());
+    loop {
+        tokio::select! {
+            (accepted, permit) = accept(&listener, &limiter) => {
+                let (inbound, addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // Out of descriptors most likely, retrying right away would only spin
+                        warn!(listener = port, error = %e, "Failed to accept a connection");
+                        time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let options = settings.options();
+                let id = inspector::next_id();
+                let span = info_span!(
+                    "connection",
+                    id,
+                    listener = port,
+                    peer = %addr,
+                    client = field::Empty,
+                    target = field::Empty,
+                );
+                span.in_scope(|| {
+                    debug!("Connection accepted");
+                    if let Err(e) = sockets::apply(&inbound, &options.socket) {
+                        warn!(error = %e, "Failed to set socket options");
+                    }
+                });
+                let limiter = limiter.clone();
+                let events = events.clone();
+                let settings = settings.clone();
+                tokio::spawn(
+                    handle_connection(inbound, addr, id, offset, limiter, permit, events, settings)
+                        .instrument(span),
+                );
+            },
+
+            _ = stop_rx.changed() => break,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_connection(
+    mut inbound: TcpStream,
+    addr: SocketAddr,
+    id: u64,
+    offset: u16,
+    limiter: Arc<ConnectionLimiter>,
+    permit: Option<OwnedSemaphorePermit>,
+    events: EventSink,
+    settings: Arc<Settings>,
+) {
+    let accepted_at = Instant::now();
+    let listener = inbound.local_addr().map_or(0, |local| local.port());
+    let mut options = settings.options();
+    // Each port of a range goes to the port at the same offset from the target
+    options.fallback = options
+        .fallback
+        .and_then(|fallback| fallback.offset(offset));
+    // Clients get until then to say where they are headed, so that those never
+    // finishing their handshake do not hold on to a connection slot
+    let handshake = options
+        .timeouts
+        .connect_secs
+        .or(options.timeouts.idle_secs)
+        .map(|secs| time::Instant::from_std(accepted_at) + Duration::from_secs(secs.into()));
+    let peer = if options.accept_proxy_protocol {
+        match within(handshake, proxy_protocol::read_header(&mut inbound)).await {
+            Ok(Some(client_addr)) => {
+                Span::current().record("client", field::display(client_addr));
+                client_addr
+            }
+            Ok(None) => addr,
+            Err(e) => {
+                handshake_failed(&events, addr, e, "Invalid PROXY protocol header");
+                return;
+            }
+        }
+    } else {
+        addr
+    };
+
+    if !options.access.permits(peer.ip()) {
+        debug!("Client not allowed");
+        STATS.denied();
+        metrics::denied(listener);
+        events.emit(ProxyEvent::ConnectionRejected {
+            peer,
+            reason: RejectReason::NotAllowed,
+        });
+        return;
+    }
+    STATS.accepted();
+    metrics::accepted(listener);
+
+    let _admission = match limiter.admit(peer.ip(), permit).await {
+        Ok(admission) => admission,
+        Err(reason) => {
+            warn!(%reason, "Connection rejected");
+            STATS.rejected();
+            metrics::rejected(listener);
+            events.emit(ProxyEvent::ConnectionRejected { peer, reason });
+            return;
+        }
+    };
+
+    if faults::should_refuse(&settings.faults()) {
+        debug!("Connection refused by fault injection");
+        let _ = SockRef::from(&inbound).set_linger(Some(Duration::ZERO));
+        events.emit(ProxyEvent::FaultInjected {
+            peer,
+            fault: FaultKind::Refused,
+        });
+        return;
+    }
+
+    let read = read_destination(&mut inbound, &options, &settings, peer, offset);
+    let destination = match within(handshake, read).await {
+        Ok(Some(destination)) => destination,
+        Ok(None) => return,
+        Err(e) => {
+            handshake_failed(&events, peer, e, "Invalid request");
+            return;
+        }
+    };
+    let Some(target) = options.route(&destination.target) else {
+        info!(destination = %destination.target, "No route for destination");
+        let _ = answer(&mut inbound, options.mode, Outcome::NoRoute).await;
+        events.emit(ProxyEvent::ConnectionRejected {
+            peer,
+            reason: RejectReason::NoRoute,
+        });
+        return;
+    };
+
+    let timeouts = &options.timeouts;
+    Span::current().record(
+        "target",
+        field::display(metrics::target_name(&options, &target)),
+    );
+    let connecting = Instant::now();
+    let connected = upstream::connect(target.clone(), &options, peer, &events).await;
+    match &connected {
+        Ok((Some(_), used)) => metrics::connected(listener, &options, used, connecting.elapsed()),
+        Ok((None, _)) => {}
+        Err(_) => metrics::upstream_failed(listener, &options, &target),
+    }
+    if options.affinity_secs.is_some() && !matches!(&connected, Ok((_, used)) if *used == target) {
+        settings.affinity.unpin(peer.ip());
+    }
+    let outcome = match &connected {
+        Ok((outbound, _)) => Outcome::Connected(
+            outbound
+                .as_ref()
+                .and_then(|outbound| outbound.local_addr().ok()),
+        ),
+        Err(e) => Outcome::Failed(e.kind()),
+    };
+    if answer(&mut inbound, options.mode, outcome).await.is_err() {
+        return;
+    }
+    let entry = |target: &ForwardTarget, upstream| access_log::Entry {
+        listener,
+        client: peer,
+        target: metrics::target_name(&options, target),
+        upstream,
+        started: accepted_at,
+    };
+    let recorders = settings.recorders();
+    let (outbound, target) = match connected {
+        Ok(connected) => connected,
+        Err(e) => {
+            warn!(error = %e, "Failed to connect to the target");
+            if let Some(log) = &recorders.access_log {
+                log.record(&entry(&target, None), (0, 0), "upstream failed");
+            }
+            return;
+        }
+    };
+    let open = metrics::Open::new(listener, &options, &target);
+    let transferred = Arc::new(Transferred::default());
+    let Some(outbound) = outbound else {
+        let inbound = Counted::new(inbound, transferred.clone());
+        let served = local::serve(inbound, destination.head, handshake, &target, &options);
+        let entry = entry(&target, None);
+        let log = recorders.access_log.as_ref();
+        finish(
+            served,
+            &transferred,
+            timeouts.lifetime_secs,
+            &events,
+            &open,
+            log,
+            entry,
+        )
+        .await;
+        return;
+    };
+    let forward_addr = outbound.peer_addr().unwrap_or(addr);
+    let entry = entry(&target, Some(forward_addr));
+
+    let registered = Registered::new(id, peer, target);
+    let forwarded = async {
+        let head = destination.head;
+        #[cfg(target_os = "linux")]
+        let head = if options.zero_copy && !needs_user_space(&options, &settings) {
+            let wanted = user_space_wanted(&registered, &settings);
+            match splice::forward(&inbound, &outbound, &head, &transferred, wanted).await? {
+                Spliced::Closed => return Ok(()),
+                Spliced::HandedOver => {
+                    debug!("Handing the spliced connection over to user space");
+                    Vec::new()
+                }
+            }
+        } else {
+            head
+        };
+
+        let mut client: Box<dyn Stream> =
+            Box::new(Faulty::new(inbound, peer, events.clone(), settings.clone()));
+        if !head.is_empty() {
+            client = Box::new(Prefixed::new(client, head));
+        }
+        let mut idle = None;
+        if let Some(idle_secs) = timeouts.idle_secs {
+            let timeout = IdleTimeout::new(client, Duration::from_secs(idle_secs.into()));
+            idle = Some(timeout.activity());
+            client = Box::new(timeout);
+        }
+        if let Some(capture) = recorders.capture.clone() {
+            client = Box::new(Recorded::new(client, capture, peer, forward_addr));
+        }
+        if let Some(sessions) = &recorders.sessions {
+            client = Box::new(SessionTap::new(client, sessions, id));
+        }
+        client = Box::new(Inspected::new(client, registered));
+        let client = Box::new(Counted::new(client, transferred.clone()));
+
+        pipe(client, Box::new(outbound), options.network.as_ref(), idle).await
+    };
+    let log = recorders.access_log.as_ref();
+    finish(
+        forwarded,
+        &transferred,
+        timeouts.lifetime_secs,
+        &events,
+        &open,
+        log,
+        entry,
+    )
+    .await;
+}
+
+/// Reports a client that did not get through its handshake.
+fn handshake_failed(events: &EventSink, peer: SocketAddr, error: Error, what: &str) {
+    if error.kind() == ErrorKind::TimedOut {
+        info!("Client did not finish its handshake in time");
+        events.emit(ProxyEvent::TimedOut {
+            peer,
+            timeout: TimeoutKind::Handshake,
+        });
+    } else {
+        warn!(error = %error, "{}", what);
+    }
+}
+
+/// Whether a connection has to go through the user-space wrappers, which see every byte.
+#[cfg(target_os = "linux")]
+fn needs_user_space(options: &ListenerOptions, settings: &Settings) -> bool {
+    options.network.is_some() || options.timeouts.idle_secs.is_some() || settings.wrappers_enabled()
+}
+
+/// Resolves once a spliced connection has to go on through user space, because it is being
+/// inspected or the wrappers it skipped were turned on.
+#[cfg(target_os = "linux")]
+async fn user_space_wanted(registered: &Registered, settings: &Settings) {
+    let mut wrappers = settings.watch_wrappers();
+    tokio::select! {
+        _ = registered.inspected() => {}
+        _ = wrappers.wait_for(|enabled| *enabled) => {}
+    }
+}
+
+/// Waits for a connection to be forwarded, within its lifetime, and reports how it ended
+/// along with what `transferred` counted by then, to `log` as well when there is one.
+async fn finish(
+    piped: impl Future<Output = std::io::Result<()>>,
+    transferred: &Transferred,
+    lifetime_secs: Option<u32>,
+    events: &EventSink,
+    open: &metrics::Open,
+    log: Option<&AccessLog>,
+    entry: access_log::Entry,
+) {
+    let piped = match lifetime_secs {
+        Some(secs) => tokio::time::timeout(Duration::from_secs(secs.into()), piped).await,
+        None => Ok(piped.await),
+    };
+
+    let peer = entry.client;
+    let (from_client, from_server) = transferred.totals();
+    open.transferred(from_client, from_server);
+    let close = match piped {
+        Ok(Ok(())) => {
+            info!(from_client, from_server, "Connection closed");
+            "closed".to_owned()
+        }
+        Ok(Err(e)) if e.kind() == ErrorKind::TimedOut => {
+            info!(from_client, from_server, "Connection closed after idling");
+            events.emit(ProxyEvent::TimedOut {
+                peer,
+                timeout: TimeoutKind::Idle,
+            });
+            TimeoutKind::Idle.to_string()
+        }
+        Ok(Err(e)) => {
+            warn!(from_client, from_server, error = %e, "Connection closed with an error");
+            e.to_string()
+        }
+        Err(_) => {
+            info!(
+                from_client,
+                from_server, "Connection closed after reaching its lifetime"
+            );
+            events.emit(ProxyEvent::TimedOut {
+                peer,
+                timeout: TimeoutKind::Lifetime,
+            });
+            TimeoutKind::Lifetime.to_string()
+        }
+    };
+    if let Some(log) = log {
+        log.record(&entry, (from_client, from_server), &close);
+    }
+}
+
+/// Where a client is headed, along with what was read off it to find out.
+struct Destination {
+    target: ForwardTarget,
+    head: Vec<u8>,
+}
+
+impl From<ForwardTarget> for Destination {
+    fn from(target: ForwardTarget) -> Self {
+        Self {
+            target,
+            head: Vec::new(),
+        }
+    }
+}
+
+/// Reads the destination requested by SOCKS5 and HTTP CONNECT clients, which is
+/// the active target or one of the targets sharing its load in forward mode.
+/// In HTTP mode the request may name another target. `None` means the
+/// client was already answered.
+async fn read_destination(
+    inbound: &mut TcpStream,
+    options: &ListenerOptions,
+    settings: &Settings,
+    peer: SocketAddr,
+    offset: u16,
+) -> std::io::Result<Option<Destination>> {
+    let destination = match options.mode {
+        ListenerMode::Forward => default_target(options, settings, peer, offset)?.into(),
+        ListenerMode::Socks5 => socks::handshake(inbound, options.socks_credentials.as_ref())
+            .await?
+            .into(),
+        ListenerMode::HttpConnect => http_connect::read_request(inbound).await?.into(),
+        ListenerMode::Http => {
+            let head = http_route::read_head(inbound).await?;
+            let named = match http_route::select(&head) {
+                Selection::Remember { name, location } => {
+                    http_route::redirect(inbound, &name, &location).await?;
+                    return Ok(None);
+                }
+                Selection::Named(name) => options
+                    .routes
+                    .iter()
+                    .find(|route| route.name == name)
+                    .and_then(|route| route.target.offset(offset)),
+                Selection::Default => None,
+            };
+            let active = settings.target().offset(offset);
+            match named {
+                // Keeping the connection would hand the next request to this target as well
+                Some(target) if Some(&target) != active.as_ref() => Destination {
+                    target,
+                    head: http_route::close_after(head),
+                },
+                _ => Destination {
+                    target: default_target(options, settings, peer, offset)?,
+                    head,
+                },
+            }
+        }
+    };
+    Ok(Some(destination))
+}
+
+fn default_target(
+    options: &ListenerOptions,
+    settings: &Settings,
+    peer: SocketAddr,
+    offset: u16,
+) -> std::io::Result<ForwardTarget> {
+    let active = settings.target();
+    let target = match options.affinity_secs {
+        Some(secs) => settings.affinity.pick(
+            active,
+            &options.routes,
+            peer.ip(),
+            Duration::from_secs(secs.into()),
+        ),
+        None => active,
+    };
+    target
+        .offset(offset)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Target port out of range"))
+}
+
+/// How getting to the requested destination went.
+enum Outcome {
+    NoRoute,
+    Connected(Option<SocketAddr>),
+    Failed(ErrorKind),
+}
+
+/// Tells SOCKS5 and HTTP CONNECT clients how their request went.
+async fn answer(
+    inbound: &mut TcpStream,
+    mode: ListenerMode,
+    outcome: Outcome,
+) -> std::io::Result<()> {
+    match mode {
+        ListenerMode::Forward | ListenerMode::Http => Ok(()),
+        ListenerMode::Socks5 => {
+            let (reply, bound) = match outcome {
+                Outcome::NoRoute => (socks::Reply::NotAllowed, None),
+                Outcome::Connected(bound) => (socks::Reply::Succeeded, bound),
+                Outcome::Failed(ErrorKind::TimedOut) => (socks::Reply::HostUnreachable, None),
+                Outcome::Failed(ErrorKind::ConnectionRefused) => {
+                    (socks::Reply::ConnectionRefused, None)
+                }
+                Outcome::Failed(_) => (socks::Reply::GeneralFailure, None),
+            };
+            socks::reply(inbound, reply, bound).await
+        }
+        ListenerMode::HttpConnect => {
+            let reply = match outcome {
+                Outcome::NoRoute => http_connect::Reply::Forbidden,
+                Outcome::Connected(_) => http_connect::Reply::Established,
+                Outcome::Failed(ErrorKind::TimedOut) => http_connect::Reply::GatewayTimeout,
+                Outcome::Failed(_) => http_connect::Reply::BadGateway,
+            };
+            http_connect::reply(inbound, reply).await
+        }
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Copies both ways until each side is done, or until the client stream's idle timeout
+/// expires when it has one.
+async fn pipe(
+    mut inbound: Box<dyn Stream>,
+    mut outbound: Box<dyn Stream>,
+    network: Option<&NetworkProfile>,
+    idle: Option<Arc<Activity>>,
+) -> std::io::Result<()> {
+    if let Some(profile) = network {
+        let latency = Duration::from_millis(profile.latency_ms.into());
+        inbound = Box::new(Shaped::new(inbound, profile.upload_kbps, latency));
+        outbound = Box::new(Shaped::new(outbound, profile.download_kbps, latency));
+    }
+
+    let copied = tokio::io::copy_bidirectional(&mut inbound, &mut outbound);
+    match idle {
+        Some(activity) => tokio::select! {
+            copied = copied => copied.map(|_| ()),
+            e = activity.expired() => Err(e),
+        },
+        None => copied.await.map(|_| ()),
+    }
+}
+
+async fn accept(
+    listener: &TcpListener,
+    limiter: &ConnectionLimiter,
+) -> (
+    std::io::Result<(TcpStream, SocketAddr)>,
+    Option<OwnedSemaphorePermit>,
+) {
+    let permit = limiter.ready().await;
+    (listener.accept().await, permit)
 }
 
 async fn create_kill_signal(mut kill_rx: Receiver<()>) {
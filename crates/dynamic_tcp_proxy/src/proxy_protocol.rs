@@ -0,0 +1,215 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Strips a PROXY v1/v2 header off `stream`, returning the client address it carries.
+/// `None` means the header holds no address (`UNKNOWN` or `LOCAL`).
+pub(crate) async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut prefix = [0; 5];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V1_PREFIX {
+        read_v1(stream, &prefix).await
+    } else if prefix == V2_SIGNATURE[..5] {
+        read_v2(stream, &prefix).await
+    } else {
+        Err(invalid("Missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<S>(stream: &mut S, prefix: &[u8]) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    // The header length is unknown upfront, read byte by byte so that no payload
+    // following the header gets swallowed.
+    let mut line = prefix.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY v1 header is not valid ASCII"))?;
+    let mut parts = line.split(' ').skip(1);
+
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("Unsupported PROXY v1 protocol")),
+    }
+
+    let src_ip: IpAddr = parse_part(parts.next())?;
+    let _dst_ip: IpAddr = parse_part(parts.next())?;
+    let src_port: u16 = parse_part(parts.next())?;
+    let _dst_port: u16 = parse_part(parts.next())?;
+
+    Ok(Some(SocketAddr::new(src_ip, src_port)))
+}
+
+async fn read_v2<S>(stream: &mut S, prefix: &[u8]) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0; 16];
+    header[..prefix.len()].copy_from_slice(prefix);
+    stream.read_exact(&mut header[prefix.len()..]).await?;
+
+    if header[..12] != V2_SIGNATURE[..] {
+        return Err(invalid("Invalid PROXY v2 signature"));
+    }
+    if header[12] >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY v2 version"));
+    }
+
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut addresses = vec![0; len];
+    stream.read_exact(&mut addresses).await?;
+
+    let is_proxy_command = header[12] & 0x0F == 1;
+    if !is_proxy_command {
+        return Ok(None);
+    }
+
+    match header[13] >> 4 {
+        1 if len >= 12 => {
+            let ip: [u8; 4] = addresses[0..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        2 if len >= 36 => {
+            let ip: [u8; 16] = addresses[0..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        1 | 2 => Err(invalid("PROXY v2 address block too short")),
+        _ => Ok(None),
+    }
+}
+
+fn parse_part<T: std::str::FromStr>(part: Option<&str>) -> Result<T, Error> {
+    part.and_then(|part| part.parse().ok())
+        .ok_or_else(|| invalid("Malformed PROXY v1 header"))
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a header off `bytes`, also returning what was left for the payload.
+    async fn parse(bytes: &[u8]) -> (Result<Option<SocketAddr>, Error>, &[u8]) {
+        let mut stream = bytes;
+        let header = read_header(&mut stream).await;
+        (header, stream)
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family << 4 | 1]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_and_leaves_the_payload() {
+        let (header, rest) = parse(b"PROXY TCP4 192.0.2.1 127.0.0.1 40000 80\r\nGET").await;
+        assert_eq!(header.unwrap(), Some("192.0.2.1:40000".parse().unwrap()));
+        assert_eq!(rest, b"GET");
+
+        let (header, _) = parse(b"PROXY TCP6 2001:db8::1 ::1 40000 80\r\n").await;
+        assert_eq!(
+            header.unwrap(),
+            Some("[2001:db8::1]:40000".parse().unwrap())
+        );
+        let (header, _) = parse(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(header.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn limits_v1_to_107_bytes() {
+        let line = |len: usize| {
+            let mut line = b"PROXY UNKNOWN".to_vec();
+            line.resize(len - 2, b' ');
+            line.extend(b"\r\n");
+            line
+        };
+        assert_eq!(parse(&line(107)).await.0.unwrap(), None);
+        let error = parse(&line(108)).await.0.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_headers() {
+        let malformed: [&[u8]; 5] = [
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"PROXY TCP4 192.0.2.1 127.0.0.1 40000\r\n",
+            b"PROXY TCP4 not-an-ip 127.0.0.1 40000 80\r\n",
+            b"PROXY UDP4 192.0.2.1 127.0.0.1 40000 80\r\n",
+            b"\r\n\r\n\0\r\nQUITX\x21\x11\0\0",
+        ];
+        for header in malformed {
+            let error = parse(header).await.0.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{:?}", header);
+        }
+        let mut version_one = v2(1, 1, &[0; 12]);
+        version_one[12] = 0x11;
+        assert!(parse(&version_one).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_v2_addresses() {
+        let ipv4 = [192, 0, 2, 1, 127, 0, 0, 1, 0x9c, 0x40, 0, 80];
+        let bytes = [v2(1, 1, &ipv4), b"GET".to_vec()].concat();
+        let (header, rest) = parse(&bytes).await;
+        assert_eq!(header.unwrap(), Some("192.0.2.1:40000".parse().unwrap()));
+        assert_eq!(rest, b"GET");
+
+        let mut ipv6 = [0; 36];
+        ipv6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6[32..34].copy_from_slice(&40000u16.to_be_bytes());
+        let (header, _) = parse(&v2(1, 2, &ipv6)).await;
+        assert_eq!(
+            header.unwrap(),
+            Some("[2001:db8::1]:40000".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn v2_local_and_unknown_families_carry_no_address() {
+        let bytes = [v2(0, 1, &[0; 12]), b"GET".to_vec()].concat();
+        let (header, rest) = parse(&bytes).await;
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"GET");
+        let (header, _) = parse(&v2(1, 0, &[])).await;
+        assert_eq!(header.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_v2_address_blocks() {
+        let error = parse(&v2(1, 1, &[0; 8])).await.0.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        let error = parse(&v2(1, 2, &[0; 12])).await.0.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        // Announcing more than is sent
+        let mut cut = v2(1, 1, &[0; 12]);
+        cut.truncate(cut.len() - 4);
+        let error = parse(&cut).await.0.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
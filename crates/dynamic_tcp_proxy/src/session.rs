@@ -0,0 +1,451 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::mpsc::{channel, Sender};
+use std::task::{ready, Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::runtime::{Handle, Runtime};
+use tracing::error;
+
+use crate::config::{ForwardTarget, ListenerOptions, TargetKind};
+use crate::local;
+use crate::passthrough::passthrough_write;
+
+// File layout: the magic, then per chunk a direction byte, the offset from the
+// start of the connection in microseconds (u64 LE), the length (u32 LE) and the data.
+const MAGIC: &[u8; 8] = b"PSSESS01";
+/// Larger than any chunk read off a connection, bounds what a damaged file can allocate
+const MAX_CHUNK_LEN: usize = 16 * 1024 * 1024;
+const REPLAY_IDLE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub offset: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Session {
+    pub chunks: Vec<Chunk>,
+}
+
+impl Session {
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Not a session recording",
+            ));
+        }
+
+        let mut chunks = Vec::new();
+        let mut direction = [0; 1];
+        while file.read(&mut direction)? == 1 {
+            let mut header = [0; 12];
+            file.read_exact(&mut header)?;
+            let offset = u64::from_le_bytes(header[..8].try_into().unwrap());
+            let len = u32::from_le_bytes(header[8..].try_into().unwrap()) as usize;
+            if len > MAX_CHUNK_LEN {
+                return Err(Error::new(ErrorKind::InvalidData, "Session chunk too long"));
+            }
+            let direction = match direction[0] {
+                0 => Direction::ClientToServer,
+                1 => Direction::ServerToClient,
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Invalid session chunk direction",
+                    ))
+                }
+            };
+            let mut data = vec![0; len];
+            file.read_exact(&mut data)?;
+
+            chunks.push(Chunk {
+                offset: Duration::from_micros(offset),
+                direction,
+                data,
+            });
+        }
+        Ok(Self { chunks })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if self
+            .chunks
+            .iter()
+            .any(|chunk| chunk.data.len() > MAX_CHUNK_LEN)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Session chunk too long",
+            ));
+        }
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        for chunk in &self.chunks {
+            write_chunk(&mut file, chunk)?;
+        }
+        file.flush()
+    }
+}
+
+fn write_chunk(file: &mut impl Write, chunk: &Chunk) -> Result<()> {
+    let direction = match chunk.direction {
+        Direction::ClientToServer => 0u8,
+        Direction::ServerToClient => 1u8,
+    };
+    file.write_all(&[direction])?;
+    file.write_all(&(chunk.offset.as_micros() as u64).to_le_bytes())?;
+    file.write_all(&(chunk.data.len() as u32).to_le_bytes())?;
+    file.write_all(&chunk.data)
+}
+
+/// Re-sends the client side of `session` to `target` with the original timing,
+/// returning what was sent along with the responses received. Local targets answer as they
+/// would through a listener with the default options. Blocks on a runtime of its own, so it
+/// fails when called from inside an async runtime.
+pub fn replay(session: &Session, target: &ForwardTarget) -> Result<Session> {
+    if Handle::try_current().is_ok() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Cannot replay a session from inside an async runtime",
+        ));
+    }
+    let runtime = Runtime::new()?;
+    runtime.block_on(replay_session(session, target))
+}
+
+type Reader = Box<dyn AsyncRead + Send + Unpin>;
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+async fn replay_session(session: &Session, target: &ForwardTarget) -> Result<Session> {
+    let (mut reader, mut writer): (Reader, Writer) = match target.kind {
+        TargetKind::Address => {
+            let stream = TcpStream::connect((target.domain.as_str(), target.port)).await?;
+            let (reader, writer) = stream.into_split();
+            (Box::new(reader), Box::new(writer))
+        }
+        TargetKind::Reject => return Err(ErrorKind::ConnectionReset.into()),
+        _ => {
+            let (client, mut server) = tokio::io::duplex(64 * 1024);
+            let target = target.clone();
+            tokio::spawn(async move {
+                let options = ListenerOptions::default();
+                local::answer(&mut server, Vec::new(), None, &target, &options).await;
+            });
+            let (reader, writer) = tokio::io::split(client);
+            (Box::new(reader), Box::new(writer))
+        }
+    };
+    let started = tokio::time::Instant::now();
+
+    let responses = tokio::spawn(async move {
+        let mut responses = Vec::new();
+        let mut buf = vec![0; 16 * 1024];
+        while let Ok(Ok(read)) = tokio::time::timeout(REPLAY_IDLE, reader.read(&mut buf)).await {
+            if read == 0 {
+                break;
+            }
+            responses.push(Chunk {
+                offset: started.elapsed(),
+                direction: Direction::ServerToClient,
+                data: buf[..read].to_vec(),
+            });
+        }
+        responses
+    });
+
+    let mut chunks = Vec::new();
+    for chunk in &session.chunks {
+        if chunk.direction != Direction::ClientToServer {
+            continue;
+        }
+        tokio::time::sleep_until(started + chunk.offset).await;
+        writer.write_all(&chunk.data).await?;
+        chunks.push(Chunk {
+            offset: started.elapsed(),
+            ..chunk.clone()
+        });
+    }
+
+    chunks.extend(responses.await?);
+    chunks.sort_by_key(|chunk| chunk.offset);
+    Ok(Session { chunks })
+}
+
+enum Record {
+    Chunk(u64, Chunk),
+    Close(u64),
+}
+
+/// Writes one session file per connection from a background thread.
+#[derive(Clone)]
+pub(crate) struct SessionRecorder {
+    records: Sender<Record>,
+    /// Folder the session files are written to
+    pub(crate) directory: PathBuf,
+}
+
+impl SessionRecorder {
+    pub(crate) fn create(directory: &Path, listen_port: u16) -> Result<Self> {
+        std::fs::create_dir_all(directory)?;
+        let folder = directory.to_path_buf();
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let (records_tx, records_rx) = channel::<Record>();
+        thread::Builder::new()
+            .name("session_writer".to_string())
+            .spawn(move || {
+                let mut files: HashMap<u64, BufWriter<File>> = HashMap::new();
+                for record in records_rx {
+                    match record {
+                        Record::Chunk(id, chunk) => {
+                            let file = match files.entry(id) {
+                                Entry::Occupied(file) => file.into_mut(),
+                                Entry::Vacant(entry) => {
+                                    let path = session_path(&folder, listen_port, started, id);
+                                    match open_session(&path) {
+                                        Ok(file) => entry.insert(file),
+                                        Err(e) => {
+                                            error!(error = %e, "Failed to create session file");
+                                            continue;
+                                        }
+                                    }
+                                }
+                            };
+                            if let Err(e) = write_chunk(file, &chunk) {
+                                error!(error = %e, "Failed to write session file");
+                            }
+                        }
+                        Record::Close(id) => {
+                            if let Some(mut file) = files.remove(&id) {
+                                let _ = file.flush();
+                            }
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            records: records_tx,
+            directory: directory.to_path_buf(),
+        })
+    }
+}
+
+fn session_path(directory: &Path, listen_port: u16, started: u64, id: u64) -> PathBuf {
+    directory.join(format!("session_{listen_port}_{started}_{id}.pssession"))
+}
+
+fn open_session(path: &Path) -> Result<BufWriter<File>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(MAGIC)?;
+    Ok(file)
+}
+
+/// Records the chunks flowing through the client side stream of a connection, in a file
+/// named after the connection's id.
+pub(crate) struct SessionTap<S> {
+    inner: S,
+    records: Sender<Record>,
+    id: u64,
+    started: Instant,
+}
+
+impl<S> SessionTap<S> {
+    pub(crate) fn new(inner: S, recorder: &SessionRecorder, id: u64) -> Self {
+        Self {
+            inner,
+            records: recorder.records.clone(),
+            id,
+            started: Instant::now(),
+        }
+    }
+
+    fn record(&self, direction: Direction, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let chunk = Chunk {
+            offset: self.started.elapsed(),
+            direction,
+            data: data.to_vec(),
+        };
+        let _ = self.records.send(Record::Chunk(self.id, chunk));
+    }
+}
+
+impl<S> Drop for SessionTap<S> {
+    fn drop(&mut self) {
+        let _ = self.records.send(Record::Close(self.id));
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SessionTap<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.record(Direction::ClientToServer, &buf.filled()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SessionTap<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.record(Direction::ServerToClient, &buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    passthrough_write!();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::CannedResponse;
+
+    use super::*;
+
+    /// Scratch folder removed along with its files once dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("port_switch_session_{name}_{}", std::process::id()));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        /// A session file holding `bytes` after the magic.
+        fn file(&self, name: &str, bytes: &[u8]) -> PathBuf {
+            let path = self.0.join(format!("{name}.pssession"));
+            std::fs::write(&path, [MAGIC.as_slice(), bytes].concat()).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn chunk(direction: u8, len: u32, data: &[u8]) -> Vec<u8> {
+        [
+            &[direction][..],
+            &7u64.to_le_bytes(),
+            &len.to_le_bytes(),
+            data,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn saves_and_loads_sessions() {
+        let session = Session {
+            chunks: vec![
+                Chunk {
+                    offset: Duration::from_micros(10),
+                    direction: Direction::ClientToServer,
+                    data: b"ping".to_vec(),
+                },
+                Chunk {
+                    offset: Duration::from_millis(3),
+                    direction: Direction::ServerToClient,
+                    data: b"pong".to_vec(),
+                },
+            ],
+        };
+        let directory = TempDir::new("round_trip");
+        let path = directory.file("round_trip", &[]);
+        session.save(&path).unwrap();
+        assert_eq!(Session::load(&path).unwrap(), session);
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let directory = TempDir::new("damaged");
+        let file = |name, bytes: &[u8]| directory.file(name, bytes);
+        let invalid = |name, bytes: &[u8]| {
+            let error = Session::load(&file(name, bytes)).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{}", name);
+        };
+        invalid("direction", &chunk(2, 4, b"data"));
+        invalid("too_long", &chunk(0, u32::MAX, b""));
+
+        let error = Session::load(&file("truncated", &chunk(0, 8, b"data"))).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        let path = file("magic", &[]);
+        std::fs::write(&path, b"PSSESS99").unwrap();
+        assert_eq!(
+            Session::load(&path).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn replays_against_local_targets() {
+        let request = Chunk {
+            offset: Duration::ZERO,
+            direction: Direction::ClientToServer,
+            data: b"GET / HTTP/1.1\r\nHost: a\r\n\r\n".to_vec(),
+        };
+        let session = Session {
+            chunks: vec![request],
+        };
+        let target = |kind| ForwardTarget {
+            kind,
+            ..Default::default()
+        };
+
+        let response = CannedResponse {
+            body: "down".to_owned(),
+            ..Default::default()
+        };
+        let replayed = replay(&session, &target(TargetKind::Respond(response))).unwrap();
+        let answered: Vec<u8> = replayed
+            .chunks
+            .iter()
+            .filter(|chunk| chunk.direction == Direction::ServerToClient)
+            .flat_map(|chunk| chunk.data.clone())
+            .collect();
+        assert!(answered.ends_with(b"\r\n\r\ndown"));
+
+        let error = replay(&session, &target(TargetKind::Reject)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn replay_refuses_to_block_a_runtime() {
+        let error = replay(&Session::default(), &ForwardTarget::default()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,238 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use tokio::sync::watch;
+use tracing::error;
+
+use crate::access_log::AccessLog;
+use crate::affinity::Affinity;
+use crate::capture::Capture;
+use crate::config::{AccessLogOptions, FaultRules, ForwardTarget, ListenerOptions};
+use crate::events::{EventSink, ProxyEvent};
+use crate::session::SessionRecorder;
+
+/// Configuration of one proxy, swapped by its update observer and read by its connections
+/// as they are accepted, or on every read and write for the fault rules. What the proxy
+/// keeps between connections is held here too, so that proxies run side by side.
+pub(crate) struct Settings {
+    target: Mutex<Option<ForwardTarget>>,
+    options: Mutex<ListenerOptions>,
+    faults: Mutex<FaultRules>,
+    recorders: Mutex<Recorders>,
+    /// Whether faults, captures or session recordings are on, which spliced connections follow
+    wrappers: watch::Sender<bool>,
+    pub(crate) affinity: Affinity,
+    /// Addresses of the running listeners, empty while turned off
+    listening: Mutex<Vec<SocketAddr>>,
+}
+
+/// Files connections are written to while recording is turned on.
+#[derive(Clone, Default)]
+pub(crate) struct Recorders {
+    pub(crate) capture: Option<Capture>,
+    pub(crate) sessions: Option<SessionRecorder>,
+    pub(crate) access_log: Option<AccessLog>,
+}
+
+impl Settings {
+    pub(crate) fn new() -> Self {
+        Self {
+            target: Mutex::new(None),
+            options: Mutex::new(ListenerOptions::default()),
+            faults: Mutex::new(FaultRules::default()),
+            recorders: Mutex::new(Recorders::default()),
+            wrappers: watch::channel(false).0,
+            affinity: Affinity::default(),
+            listening: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn target(&self) -> ForwardTarget {
+        let read_guard = self.target.lock().expect("Cannot lock target port mutex");
+        read_guard.clone().unwrap()
+    }
+
+    pub(crate) fn options(&self) -> ListenerOptions {
+        let read_guard = self
+            .options
+            .lock()
+            .expect("Cannot lock listener options mutex");
+        read_guard.clone()
+    }
+
+    pub(crate) fn faults(&self) -> FaultRules {
+        *self.faults.lock().expect("Cannot lock fault rules mutex")
+    }
+
+    pub(crate) fn recorders(&self) -> Recorders {
+        let read_guard = self.recorders.lock().expect("Cannot lock recorders mutex");
+        read_guard.clone()
+    }
+
+    pub(crate) fn listening(&self) -> Vec<SocketAddr> {
+        let read_guard = self.listening.lock().expect("Cannot lock listening mutex");
+        read_guard.clone()
+    }
+
+    pub(crate) fn set_listening(&self, addrs: Vec<SocketAddr>) {
+        *self.listening.lock().expect("Cannot lock listening mutex") = addrs;
+    }
+
+    /// Switches to `target` and `options`, opening or closing the recorders they ask for.
+    pub(crate) fn apply(
+        &self,
+        target: ForwardTarget,
+        options: &ListenerOptions,
+        listen_port: u16,
+        events: &EventSink,
+    ) {
+        *self.target.lock().expect("Cannot lock target port mutex") = Some(target);
+        *self.faults.lock().expect("Cannot lock fault rules mutex") = options.faults;
+        *self
+            .options
+            .lock()
+            .expect("Cannot lock listener options mutex") = options.clone();
+        self.recorders
+            .lock()
+            .expect("Cannot lock recorders mutex")
+            .update(options, listen_port, events);
+        self.update_wrappers();
+    }
+
+    /// Closes the recorders of a proxy turned off.
+    pub(crate) fn stop_recording(&self) {
+        *self.recorders.lock().expect("Cannot lock recorders mutex") = Recorders::default();
+        self.update_wrappers();
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) fn wrappers_enabled(&self) -> bool {
+        *self.wrappers.borrow()
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) fn watch_wrappers(&self) -> watch::Receiver<bool> {
+        self.wrappers.subscribe()
+    }
+
+    /// Tells the watchers of `wrappers` when the faults, capture or sessions were toggled.
+    fn update_wrappers(&self) {
+        let recorders = self.recorders();
+        let enabled = self.faults().any_enabled()
+            || recorders.capture.is_some()
+            || recorders.sessions.is_some();
+        self.wrappers
+            .send_if_modified(|current| std::mem::replace(current, enabled) != enabled);
+    }
+}
+
+impl Recorders {
+    fn update(&mut self, options: &ListenerOptions, listen_port: u16, events: &EventSink) {
+        let directory = options.capture_dir.as_deref();
+        self.update_capture(options.record, directory, listen_port, events);
+        self.update_sessions(options.record_sessions, directory, listen_port, events);
+        self.update_access_log(options.access_log, directory, listen_port, events);
+    }
+
+    fn update_capture(
+        &mut self,
+        record: bool,
+        directory: Option<&Path>,
+        listen_port: u16,
+        events: &EventSink,
+    ) {
+        if !record {
+            self.capture = None;
+            return;
+        }
+        let directory = directory.map_or_else(std::env::temp_dir, Path::to_path_buf);
+        // A capture moved to another folder starts over in a new file there
+        if self
+            .capture
+            .as_ref()
+            .is_some_and(|capture| capture.directory == directory)
+        {
+            return;
+        }
+
+        match Capture::create(&directory, listen_port) {
+            Ok((capture, path)) => {
+                events.emit(ProxyEvent::CaptureStarted(path));
+                self.capture = Some(capture);
+            }
+            Err(e) => {
+                error!(error = %e, "Failed to create capture file");
+                events.emit(ProxyEvent::CaptureFailed(e.to_string()));
+            }
+        }
+    }
+
+    fn update_sessions(
+        &mut self,
+        record: bool,
+        directory: Option<&Path>,
+        listen_port: u16,
+        events: &EventSink,
+    ) {
+        if !record {
+            self.sessions = None;
+            return;
+        }
+        let directory = directory.map_or_else(std::env::temp_dir, Path::to_path_buf);
+        if self
+            .sessions
+            .as_ref()
+            .is_some_and(|recorder| recorder.directory == directory)
+        {
+            return;
+        }
+
+        match SessionRecorder::create(&directory, listen_port) {
+            Ok(recorder) => {
+                events.emit(ProxyEvent::SessionRecordingStarted(directory));
+                self.sessions = Some(recorder);
+            }
+            Err(e) => {
+                error!(error = %e, "Failed to start session recording");
+                events.emit(ProxyEvent::CaptureFailed(e.to_string()));
+            }
+        }
+    }
+
+    fn update_access_log(
+        &mut self,
+        options: Option<AccessLogOptions>,
+        directory: Option<&Path>,
+        listen_port: u16,
+        events: &EventSink,
+    ) {
+        let Some(options) = options else {
+            self.access_log = None;
+            return;
+        };
+        let directory = directory.map_or_else(std::env::temp_dir, Path::to_path_buf);
+        // Only a new folder needs a new file, rotation settings apply to the open one
+        if let Some(log) = self
+            .access_log
+            .as_mut()
+            .filter(|log| log.directory == directory)
+        {
+            if log.options != options {
+                log.set_options(options);
+            }
+            return;
+        }
+
+        match AccessLog::create(&directory, listen_port, options) {
+            Ok((log, path)) => {
+                events.emit(ProxyEvent::AccessLogStarted(path));
+                self.access_log = Some(log);
+            }
+            Err(e) => {
+                error!(error = %e, "Failed to open access log");
+                events.emit(ProxyEvent::AccessLogFailed(e.to_string()));
+            }
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{Error, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::passthrough::passthrough_write;
+
+const CHUNK_SIZE: usize = 16 * 1024;
+const MAX_IN_FLIGHT: usize = 256 * 1024;
+const MIN_GRANT: f64 = 1024.0;
+
+/// Delays and rate limits everything read from `inner`, writes pass through untouched.
+/// Reads are pulled eagerly into a queue so the added latency does not cap throughput.
+pub(crate) struct Shaped<S> {
+    inner: S,
+    rate: Option<ByteBucket>,
+    latency: Duration,
+    /// Bytes read and not handed out yet, in one buffer reused for the whole connection
+    in_flight: VecDeque<u8>,
+    /// When each chunk of `in_flight` is due and how much of it is left
+    chunks: VecDeque<(Instant, usize)>,
+    eof: bool,
+    /// Reported once the chunks read before it were handed out
+    error: Option<Error>,
+    timer: Pin<Box<Sleep>>,
+    /// Reads land here first, only what was read gets copied into the queue
+    scratch: Box<[u8]>,
+}
+
+impl<S> Shaped<S> {
+    pub(crate) fn new(inner: S, kbps: Option<u32>, latency: Duration) -> Self {
+        Self {
+            inner,
+            rate: kbps.map(ByteBucket::from_kbps),
+            latency,
+            in_flight: VecDeque::new(),
+            chunks: VecDeque::new(),
+            eof: false,
+            error: None,
+            timer: Box::pin(tokio::time::sleep(Duration::ZERO)),
+            scratch: vec![0; CHUNK_SIZE].into_boxed_slice(),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Shaped<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let now = Instant::now();
+            let mut wake_at = None;
+
+            while !this.eof && this.error.is_none() && this.in_flight.len() < MAX_IN_FLIGHT {
+                let allowance = match &mut this.rate {
+                    Some(rate) => match rate.available(now) {
+                        Some(available) => available.min(CHUNK_SIZE),
+                        None => {
+                            wake_at = Some(rate.next_refill(now));
+                            break;
+                        }
+                    },
+                    None => CHUNK_SIZE,
+                };
+
+                let mut chunk_buf = ReadBuf::new(&mut this.scratch[..allowance]);
+                match Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf) {
+                    Poll::Ready(Ok(())) => {
+                        let chunk = chunk_buf.filled();
+                        let read = chunk.len();
+                        if read == 0 {
+                            this.eof = true;
+                            break;
+                        }
+                        if let Some(rate) = &mut this.rate {
+                            rate.consume(read);
+                        }
+                        this.in_flight.extend(chunk);
+                        this.chunks.push_back((now + this.latency, read));
+                    }
+                    Poll::Ready(Err(e)) => this.error = Some(e),
+                    Poll::Pending => break,
+                }
+            }
+
+            match this.chunks.front_mut() {
+                Some((due, left)) if *due <= now => {
+                    let len = (*left).min(buf.remaining());
+                    // The queue wraps around, its start may be split in two
+                    let (head, tail) = this.in_flight.as_slices();
+                    let from_head = len.min(head.len());
+                    buf.put_slice(&head[..from_head]);
+                    buf.put_slice(&tail[..len - from_head]);
+                    this.in_flight.drain(..len);
+                    *left -= len;
+                    if *left == 0 {
+                        this.chunks.pop_front();
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                Some((due, _)) => {
+                    wake_at = Some(wake_at.map_or(*due, |at: Instant| at.min(*due)));
+                }
+                None => {
+                    if let Some(e) = this.error.take() {
+                        return Poll::Ready(Err(e));
+                    }
+                    if this.eof {
+                        return Poll::Ready(Ok(()));
+                    }
+                }
+            }
+
+            if let Some(wake_at) = wake_at {
+                this.timer.as_mut().reset(wake_at);
+                if this.timer.as_mut().poll(cx).is_ready() {
+                    continue;
+                }
+            }
+            return Poll::Pending;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Shaped<S> {
+    passthrough_write!(poll_write);
+}
+
+struct ByteBucket {
+    bytes_per_sec: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl ByteBucket {
+    fn from_kbps(kbps: u32) -> Self {
+        let bytes_per_sec = (kbps.max(1) as f64) * 1000.0 / 8.0;
+        // Allow bursts of about 50ms worth of traffic
+        let capacity = (bytes_per_sec / 20.0).max(MIN_GRANT);
+        Self {
+            bytes_per_sec,
+            capacity,
+            tokens: capacity,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Bytes that may be read right now, held back until a reasonably sized grant builds up.
+    fn available(&mut self, now: Instant) -> Option<usize> {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.capacity);
+        self.refilled_at = now;
+        (self.tokens >= MIN_GRANT).then_some(self.tokens as usize)
+    }
+
+    fn next_refill(&self, now: Instant) -> Instant {
+        let missing = (MIN_GRANT - self.tokens).max(0.0);
+        now + Duration::from_secs_f64(missing / self.bytes_per_sec)
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    /// Gives out its chunks one read at a time, then fails.
+    struct Failing(VecDeque<Vec<u8>>);
+
+    impl AsyncRead for Failing {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<Result<()>> {
+            match self.get_mut().0.pop_front() {
+                Some(chunk) => {
+                    buf.put_slice(&chunk);
+                    Poll::Ready(Ok(()))
+                }
+                None => Poll::Ready(Err(ErrorKind::ConnectionReset.into())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn hands_out_what_was_read_before_an_error() {
+        let chunks = [b"first ".to_vec(), b"second".to_vec()];
+        let mut shaped = Shaped::new(Failing(chunks.into()), None, Duration::from_millis(10));
+        let mut received = Vec::new();
+        let mut buf = [0; 4];
+        let error = loop {
+            match shaped.read(&mut buf).await {
+                Ok(read) => received.extend_from_slice(&buf[..read]),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(received, b"first second");
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+    }
+}
@@ -0,0 +1,62 @@
+use std::io::Result;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+use crate::config::{Keepalive, SocketOptions};
+
+/// Same as tokio's default for `TcpListener::bind`.
+const DEFAULT_BACKLOG: u32 = 1024;
+
+/// Binds a listener, its buffer sizes being inherited by the connections it accepts.
+pub(crate) fn bind(addr: SocketAddr, options: &SocketOptions) -> Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(true)?;
+    #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+    socket.set_reuseport(options.reuse_port)?;
+    if let Some(size) = options.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = options.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    socket.bind(addr)?;
+    socket.listen(options.backlog.unwrap_or(DEFAULT_BACKLOG))
+}
+
+/// Applies the per connection options to an accepted or upstream stream.
+pub(crate) fn apply(stream: &TcpStream, options: &SocketOptions) -> Result<()> {
+    stream.set_nodelay(options.nodelay)?;
+    let socket = SockRef::from(stream);
+    if let Some(keepalive) = &options.keepalive {
+        socket.set_tcp_keepalive(&tcp_keepalive(keepalive))?;
+    }
+    if let Some(size) = options.send_buffer {
+        socket.set_send_buffer_size(size as usize)?;
+    }
+    if let Some(size) = options.recv_buffer {
+        socket.set_recv_buffer_size(size as usize)?;
+    }
+    Ok(())
+}
+
+fn tcp_keepalive(keepalive: &Keepalive) -> TcpKeepalive {
+    let params = TcpKeepalive::new().with_time(Duration::from_secs(keepalive.time_secs.into()));
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    let params = match keepalive.interval_secs {
+        Some(secs) => params.with_interval(Duration::from_secs(secs.into())),
+        None => params,
+    };
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    let params = match keepalive.retries {
+        Some(retries) => params.with_retries(retries),
+        None => params,
+    };
+    params
+}
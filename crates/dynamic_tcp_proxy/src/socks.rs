@@ -0,0 +1,286 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::{Credentials, ForwardTarget};
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+
+const METHOD_NONE: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xFF;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Reply codes sent back to the client once the destination was dealt with.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+/// Runs the SOCKS5 greeting, optional username/password authentication and request,
+/// returning the destination the client asked to CONNECT to. No reply has been sent
+/// for the request yet, that is up to the caller through `reply`.
+pub(crate) async fn handshake<S>(
+    stream: &mut S,
+    credentials: Option<&Credentials>,
+) -> Result<ForwardTarget, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut greeting = [0; 2];
+    stream.read_exact(&mut greeting).await?;
+    if greeting[0] != VERSION {
+        return Err(invalid("Not a SOCKS5 greeting"));
+    }
+    let mut methods = vec![0; greeting[1].into()];
+    stream.read_exact(&mut methods).await?;
+
+    let method = if credentials.is_some() {
+        METHOD_PASSWORD
+    } else {
+        METHOD_NONE
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, METHOD_UNACCEPTABLE]).await?;
+        return Err(invalid("No acceptable SOCKS5 authentication method"));
+    }
+    stream.write_all(&[VERSION, method]).await?;
+
+    if let Some(credentials) = credentials {
+        authenticate(stream, credentials).await?;
+    }
+
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+    if request[0] != VERSION {
+        return Err(invalid("Invalid SOCKS5 request"));
+    }
+    if request[1] != CMD_CONNECT {
+        reply(stream, Reply::CommandNotSupported, None).await?;
+        return Err(invalid("Only SOCKS5 CONNECT is supported"));
+    }
+
+    let domain = match request[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut domain = vec![0; len.into()];
+            stream.read_exact(&mut domain).await?;
+            String::from_utf8(domain).map_err(|_| invalid("SOCKS5 domain is not valid UTF-8"))?
+        }
+        _ => {
+            reply(stream, Reply::AddressTypeNotSupported, None).await?;
+            return Err(invalid("Unsupported SOCKS5 address type"));
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    Ok(ForwardTarget {
+        domain,
+        port,
+        ..Default::default()
+    })
+}
+
+async fn authenticate<S>(stream: &mut S, credentials: &Credentials) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if stream.read_u8().await? != AUTH_VERSION {
+        return Err(invalid("Invalid SOCKS5 authentication version"));
+    }
+    let username = read_field(stream).await?;
+    let password = read_field(stream).await?;
+
+    // Both compared in full so that the time taken does not give away which one was wrong
+    let valid = same_bytes(&username, credentials.username.as_bytes())
+        & same_bytes(&password, credentials.password.as_bytes());
+    if !valid {
+        stream.write_all(&[AUTH_VERSION, 0x01]).await?;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "Invalid SOCKS5 credentials",
+        ));
+    }
+    stream.write_all(&[AUTH_VERSION, 0x00]).await
+}
+
+/// Compares in a time that depends on the lengths only, not on where the bytes differ.
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+async fn read_field<S>(stream: &mut S) -> Result<Vec<u8>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = stream.read_u8().await?;
+    let mut field = vec![0; len.into()];
+    stream.read_exact(&mut field).await?;
+    Ok(field)
+}
+
+/// Answers the CONNECT request, `bound` being the local address of the upstream connection.
+pub(crate) async fn reply<S>(
+    stream: &mut S,
+    reply: Reply,
+    bound: Option<SocketAddr>,
+) -> Result<(), Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    let bound = bound.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let mut message = vec![VERSION, reply as u8, 0];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            message.push(ATYP_IPV4);
+            message.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            message.push(ATYP_IPV6);
+            message.extend_from_slice(&ip.octets());
+        }
+    }
+    message.extend_from_slice(&bound.port().to_be_bytes());
+    stream.write_all(&message).await
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    /// Runs the handshake on everything `sent` by a client, also returning what it was answered.
+    async fn run(
+        sent: &[u8],
+        credentials: Option<&Credentials>,
+    ) -> (Result<ForwardTarget, Error>, Vec<u8>) {
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(sent).await.unwrap();
+        let target = handshake(&mut server, credentials).await;
+        drop(server);
+        let mut answered = Vec::new();
+        client.read_to_end(&mut answered).await.unwrap();
+        (target, answered)
+    }
+
+    fn credentials() -> Credentials {
+        Credentials {
+            username: "user".to_owned(),
+            password: "secret".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_each_address_type() {
+        let ipv4 = b"\x05\x01\x00\x05\x01\x00\x01\xc0\x00\x02\x01\x00\x50";
+        let (target, answered) = run(ipv4, None).await;
+        let target = target.unwrap();
+        assert_eq!((target.domain.as_str(), target.port), ("192.0.2.1", 80));
+        assert_eq!(answered, [VERSION, METHOD_NONE]);
+
+        let domain = b"\x05\x01\x00\x05\x01\x00\x03\x0bexample.com\x01\xbb";
+        let target = run(domain, None).await.0.unwrap();
+        assert_eq!((target.domain.as_str(), target.port), ("example.com", 443));
+
+        let mut ipv6 = b"\x05\x01\x00\x05\x01\x00\x04".to_vec();
+        ipv6.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend(8080u16.to_be_bytes());
+        let target = run(&ipv6, None).await.0.unwrap();
+        assert_eq!((target.domain.as_str(), target.port), ("2001:db8::1", 8080));
+    }
+
+    #[tokio::test]
+    async fn checks_credentials() {
+        let request = b"\x05\x01\x00\x01\x7f\x00\x00\x01\x00\x50";
+        let login = |password: &[u8]| {
+            let mut sent = b"\x05\x01\x02\x01\x04user".to_vec();
+            sent.push(password.len() as u8);
+            sent.extend(password);
+            sent.extend(request);
+            sent
+        };
+
+        let (target, answered) = run(&login(b"secret"), Some(&credentials())).await;
+        assert_eq!(target.unwrap().port, 80);
+        assert_eq!(answered, [VERSION, METHOD_PASSWORD, AUTH_VERSION, 0x00]);
+
+        for wrong in [&b"wrong"[..], b"secreT", b"secrets", b""] {
+            let (target, answered) = run(&login(wrong), Some(&credentials())).await;
+            assert_eq!(target.unwrap_err().kind(), ErrorKind::PermissionDenied);
+            assert_eq!(answered, [VERSION, METHOD_PASSWORD, AUTH_VERSION, 0x01]);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_clients_without_an_acceptable_method() {
+        let (target, answered) = run(b"\x05\x01\x00", Some(&credentials())).await;
+        assert_eq!(target.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(answered, [VERSION, METHOD_UNACCEPTABLE]);
+
+        let (target, answered) = run(b"\x04\x01\x00\x50", None).await;
+        assert!(target.is_err() && answered.is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_unsupported_requests() {
+        let bind = b"\x05\x01\x00\x05\x02\x00\x01\x7f\x00\x00\x01\x00\x50";
+        let (target, answered) = run(bind, None).await;
+        assert!(target.is_err());
+        assert_eq!(answered[2..4], [VERSION, Reply::CommandNotSupported as u8]);
+
+        let unknown_type = b"\x05\x01\x00\x05\x01\x00\x09";
+        let (target, answered) = run(unknown_type, None).await;
+        assert!(target.is_err());
+        assert_eq!(
+            answered[2..4],
+            [VERSION, Reply::AddressTypeNotSupported as u8]
+        );
+    }
+
+    #[tokio::test]
+    async fn replies_with_the_bound_address() {
+        let mut sent = Vec::new();
+        reply(
+            &mut sent,
+            Reply::Succeeded,
+            Some("192.0.2.1:8080".parse().unwrap()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(sent, b"\x05\x00\x00\x01\xc0\x00\x02\x01\x1f\x90");
+
+        let mut sent = Vec::new();
+        reply(&mut sent, Reply::HostUnreachable, None)
+            .await
+            .unwrap();
+        assert_eq!(sent, b"\x05\x04\x00\x01\x00\x00\x00\x00\x00\x00");
+    }
+}
@@ -0,0 +1,158 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+
+use socket2::SockRef;
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+
+use crate::stats::Transferred;
+
+/// Bytes moved per `splice(2)` call, the default capacity of a pipe.
+const CHUNK: usize = 64 * 1024;
+
+/// How splicing a connection ended.
+pub(crate) enum Spliced {
+    /// Both sides are done
+    Closed,
+    /// Stopped between chunks for the connection to go on through user space
+    HandedOver,
+}
+
+/// Forwards both directions through kernel pipes until each side is done, sending `head`
+/// upstream first, or until `handover` resolves. Bytes are added to `transferred` as they
+/// are moved.
+pub(crate) async fn forward(
+    inbound: &TcpStream,
+    outbound: &TcpStream,
+    head: &[u8],
+    transferred: &Transferred,
+    handover: impl Future<Output = ()>,
+) -> Result<Spliced> {
+    write_all(outbound, head).await?;
+    transferred.client_sent(head.len() as u64);
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let both = async {
+        tokio::try_join!(
+            one_way(
+                inbound,
+                outbound,
+                |bytes| transferred.client_sent(bytes),
+                stop_rx.clone()
+            ),
+            one_way(
+                outbound,
+                inbound,
+                |bytes| transferred.server_sent(bytes),
+                stop_rx.clone()
+            )
+        )
+    };
+    tokio::pin!(both);
+    let ended = tokio::select! {
+        ended = &mut both => ended?,
+        () = handover => {
+            let _ = stop_tx.send(true);
+            both.await?
+        }
+    };
+    match ended {
+        (Spliced::Closed, Spliced::Closed) => Ok(Spliced::Closed),
+        _ => Ok(Spliced::HandedOver),
+    }
+}
+
+async fn write_all(to: &TcpStream, mut data: &[u8]) -> Result<()> {
+    while !data.is_empty() {
+        to.writable().await?;
+        match to.try_write(data) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(written) => data = &data[written..],
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Splices `from` into `to` until `from` is done, then shuts down the writing half of `to`.
+/// Stops without shutting anything down once `stop` is set, the pipe being empty by then.
+async fn one_way(
+    from: &TcpStream,
+    to: &TcpStream,
+    moved: impl Fn(u64),
+    mut stop: watch::Receiver<bool>,
+) -> Result<Spliced> {
+    let pipe = Pipe::new()?;
+    loop {
+        let mut pending = loop {
+            tokio::select! {
+                biased;
+                Ok(_) = stop.wait_for(|stop| *stop) => return Ok(Spliced::HandedOver),
+                ready = from.readable() => ready?,
+            }
+            match from.try_io(Interest::READABLE, || pipe.fill(from.as_raw_fd())) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                read => break read?,
+            }
+        };
+        if pending == 0 {
+            break;
+        }
+        // The pipe is drained before reading again so it never holds more than a chunk
+        while pending > 0 {
+            to.writable().await?;
+            match to.try_io(Interest::WRITABLE, || pipe.drain(to.as_raw_fd(), pending)) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "Peer stopped reading")),
+                written => {
+                    let written = written?;
+                    pending -= written;
+                    moved(written as u64);
+                }
+            }
+        }
+    }
+    SockRef::from(to).shutdown(Shutdown::Write)?;
+    Ok(Spliced::Closed)
+}
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        // Both descriptors were just opened and are owned by nothing else
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        Ok(Self { read, write })
+    }
+
+    /// Moves what the socket has to offer into the pipe.
+    fn fill(&self, socket: RawFd) -> Result<usize> {
+        splice(socket, self.write.as_raw_fd(), CHUNK)
+    }
+
+    /// Moves up to `len` bytes out of the pipe into the socket.
+    fn drain(&self, socket: RawFd, len: usize) -> Result<usize> {
+        splice(self.read.as_raw_fd(), socket, len)
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    let moved = unsafe { libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, flags) };
+    match moved {
+        -1 => Err(Error::last_os_error()),
+        moved => Ok(moved as usize),
+    }
+}
@@ -0,0 +1,209 @@
+use std::path::{Path, PathBuf};
+
+use tokio::fs::File;
+
+use crate::config::DirectoryTarget;
+use crate::http_route::percent_decode;
+use crate::local::{Body, Response};
+
+/// Answers a GET or HEAD request with a file from `directory`.
+pub(crate) async fn respond(directory: &DirectoryTarget, head: &[u8]) -> Response {
+    let head = String::from_utf8_lossy(head);
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or("/");
+    if method != "GET" && method != "HEAD" {
+        return status_only(405);
+    }
+
+    let path = target.split(['?', '#']).next().unwrap_or_default();
+    let Some(relative) = relative_path(&percent_decode(path)) else {
+        return status_only(404);
+    };
+
+    let mut file = directory.root.join(&relative);
+    if tokio::fs::metadata(&file)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir())
+    {
+        file.push(&directory.index);
+    }
+    if let Some(response) = file_response(&directory.root, &file).await {
+        return response;
+    }
+
+    // Missing assets stay errors, only extensionless paths are app routes
+    if directory.spa_fallback && relative.extension().is_none() {
+        let index = directory.root.join(&directory.index);
+        if let Some(response) = file_response(&directory.root, &index).await {
+            return response;
+        }
+    }
+    status_only(404)
+}
+
+/// Turns a decoded request path into one relative to the served directory,
+/// refusing anything that climbs out of it.
+fn relative_path(path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            segment if segment.contains(['\\', ':', '\0']) => return None,
+            segment => relative.push(segment),
+        }
+    }
+    Some(relative)
+}
+
+/// Opens `file` for streaming when it is a regular file that still lies inside `root`
+/// once symlinks are resolved.
+async fn file_response(root: &Path, file: &Path) -> Option<Response> {
+    let root = tokio::fs::canonicalize(root).await.ok()?;
+    let resolved = tokio::fs::canonicalize(file).await.ok()?;
+    if !resolved.starts_with(root) {
+        return None;
+    }
+    let opened = File::open(&resolved).await.ok()?;
+    let metadata = opened.metadata().await.ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    Some(Response {
+        status: 200,
+        headers: vec![("Content-Type".to_owned(), mime_type(file).to_owned())],
+        body: Body::File(opened, metadata.len()),
+    })
+}
+
+fn status_only(status: u16) -> Response {
+    Response {
+        status,
+        headers: Vec::new(),
+        body: Vec::new().into(),
+    }
+}
+
+fn mime_type(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "webmanifest" => "application/manifest+json",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[test]
+    fn relative_path_stays_inside() {
+        assert_eq!(
+            relative_path("/assets/./app.js"),
+            Some(PathBuf::from("assets/app.js"))
+        );
+        assert_eq!(relative_path("/"), Some(PathBuf::new()));
+        assert_eq!(relative_path("/assets/../../etc/passwd"), None);
+        assert_eq!(relative_path(&percent_decode("/%2e%2e/etc/passwd")), None);
+        assert_eq!(
+            relative_path(&percent_decode("/a%2f..%2f..%2fsecret")),
+            None
+        );
+        assert_eq!(relative_path("/C:/Windows"), None);
+        assert_eq!(relative_path("/..\\secret"), None);
+        // Leading slashes only separate segments, an absolute path stays relative
+        assert_eq!(
+            relative_path("//etc/passwd"),
+            Some(PathBuf::from("etc/passwd"))
+        );
+    }
+
+    /// A scratch directory served as the root, with a secret next to it.
+    fn site(name: &str) -> DirectoryTarget {
+        let base =
+            std::env::temp_dir().join(format!("port_switch_static_{name}_{}", std::process::id()));
+        let root = base.join("site");
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        std::fs::write(root.join("index.html"), "index").unwrap();
+        std::fs::write(root.join("assets/app.js"), "app").unwrap();
+        std::fs::write(base.join("secret.txt"), "secret").unwrap();
+        DirectoryTarget {
+            root,
+            ..Default::default()
+        }
+    }
+
+    async fn get(directory: &DirectoryTarget, path: &str) -> (u16, Vec<u8>) {
+        let head = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let response = respond(directory, head.as_bytes()).await;
+        let body = match response.body {
+            Body::Bytes(bytes) => bytes,
+            Body::File(mut file, _) => {
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes).await.unwrap();
+                bytes
+            }
+        };
+        (response.status, body)
+    }
+
+    #[tokio::test]
+    async fn serves_files_and_falls_back_to_the_index() {
+        let directory = site("fallback");
+        assert_eq!(
+            get(&directory, "/assets/app.js").await,
+            (200, b"app".to_vec())
+        );
+        assert_eq!(get(&directory, "/").await, (200, b"index".to_vec()));
+        assert_eq!(
+            get(&directory, "/some/route").await,
+            (200, b"index".to_vec())
+        );
+        assert_eq!(get(&directory, "/assets/missing.js").await.0, 404);
+
+        let strict = DirectoryTarget {
+            spa_fallback: false,
+            ..directory
+        };
+        assert_eq!(get(&strict, "/some/route").await.0, 404);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refuses_symlinks_out_of_the_root() {
+        let directory = site("symlink");
+        let link = directory.root.join("leak.txt");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink("../secret.txt", &link).unwrap();
+        assert_eq!(get(&directory, "/leak.txt").await.0, 404);
+    }
+}
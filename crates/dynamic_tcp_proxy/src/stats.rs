@@ -0,0 +1,129 @@
+use std::io::Result;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::passthrough::passthrough_write;
+
+pub(crate) static STATS: Counters = Counters::new();
+
+/// Connection counts since the listener was last turned on.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ProxyStats {
+    pub accepted: u64,
+    /// Closed because the client address is not allowed
+    pub denied: u64,
+    /// Closed by the connection limits
+    pub rejected: u64,
+}
+
+pub(crate) struct Counters {
+    accepted: AtomicU64,
+    denied: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            accepted: AtomicU64::new(0),
+            denied: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn denied(&self) {
+        self.denied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reset(&self) {
+        self.accepted.store(0, Ordering::Relaxed);
+        self.denied.store(0, Ordering::Relaxed);
+        self.rejected.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> ProxyStats {
+        ProxyStats {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            denied: self.denied.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Bytes a connection moved each way so far, kept however the connection ends.
+#[derive(Default)]
+pub(crate) struct Transferred {
+    from_client: AtomicU64,
+    from_server: AtomicU64,
+}
+
+impl Transferred {
+    pub(crate) fn client_sent(&self, bytes: u64) {
+        self.from_client.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn server_sent(&self, bytes: u64) {
+        self.from_server.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn totals(&self) -> (u64, u64) {
+        (
+            self.from_client.load(Ordering::Relaxed),
+            self.from_server.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Counts what is read from the client stream and written back to it.
+pub(crate) struct Counted<S> {
+    inner: S,
+    transferred: Arc<Transferred>,
+}
+
+impl<S> Counted<S> {
+    pub(crate) fn new(inner: S, transferred: Arc<Transferred>) -> Self {
+        Self { inner, transferred }
+    }
+
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.transferred
+            .client_sent((buf.filled().len() - filled) as u64);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.transferred.server_sent(written as u64);
+        Poll::Ready(Ok(written))
+    }
+
+    passthrough_write!();
+}
@@ -0,0 +1,103 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+
+use crate::passthrough::passthrough_write;
+
+/// Fails `io` with `ErrorKind::TimedOut` when it is still going at `deadline`.
+pub(crate) async fn within<T>(
+    deadline: Option<Instant>,
+    io: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(deadline) = deadline else {
+        return io.await;
+    };
+    tokio::time::timeout_at(deadline, io)
+        .await
+        .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "Handshake timed out")))
+}
+
+/// Notes when anything was last read from or written to `inner`, which `Activity::expired`
+/// turns into a deadline. It is awaited next to the connection rather than checked on its
+/// polls, which stop while a direction waits on the other side, as after a half close.
+pub(crate) struct IdleTimeout<S> {
+    inner: S,
+    activity: Arc<Activity>,
+}
+
+pub(crate) struct Activity {
+    idle: Duration,
+    started: Instant,
+    /// Milliseconds from `started` to the last read or write
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn deadline(&self) -> Instant {
+        self.started + Duration::from_millis(self.last.load(Ordering::Relaxed)) + self.idle
+    }
+
+    /// Resolves once nothing has been read or written for the idle timeout.
+    pub(crate) async fn expired(&self) -> Error {
+        loop {
+            let deadline = self.deadline();
+            if deadline <= Instant::now() {
+                return Error::new(ErrorKind::TimedOut, "Connection idle");
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+impl<S> IdleTimeout<S> {
+    pub(crate) fn new(inner: S, idle: Duration) -> Self {
+        Self {
+            inner,
+            activity: Arc::new(Activity {
+                idle,
+                started: Instant::now(),
+                last: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub(crate) fn activity(&self) -> Arc<Activity> {
+        self.activity.clone()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.activity.touch();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.activity.touch();
+        Poll::Ready(Ok(written))
+    }
+
+    passthrough_write!();
+}
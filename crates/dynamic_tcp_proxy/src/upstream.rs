@@ -0,0 +1,92 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+use crate::config::{ForwardTarget, ListenerOptions};
+use crate::events::{EventSink, ProxyEvent, TimeoutKind};
+use crate::sockets;
+
+/// Connects to `target`, retrying with exponential backoff while the client waits,
+/// then to the fallback target once the retries are used up. No stream is returned
+/// for targets the proxy answers itself.
+pub(crate) async fn connect(
+    target: ForwardTarget,
+    options: &ListenerOptions,
+    peer: SocketAddr,
+    events: &EventSink,
+) -> Result<(Option<TcpStream>, ForwardTarget)> {
+    if target.is_local() {
+        return Ok((None, target));
+    }
+    let retry = &options.retry;
+    let mut backoff = Duration::from_millis(retry.initial_backoff_ms.into());
+    let max_backoff = Duration::from_millis(retry.max_backoff_ms.into());
+
+    let mut attempt = 0;
+    let error = loop {
+        match connect_once(&target, options).await {
+            Ok(stream) => return Ok((Some(stream), target)),
+            Err(e) if attempt < retry.attempts => {
+                attempt += 1;
+                warn!(upstream = %target, error = %e, attempt, ?backoff, "Failed to connect, retrying");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(max_backoff);
+            }
+            Err(e) => break e,
+        }
+    };
+    report(&error, peer, events);
+
+    let Some(fallback) = options.fallback.clone() else {
+        return Err(error);
+    };
+    let connected = match fallback.is_local() {
+        true => Ok(None),
+        false => connect_once(&fallback, options).await.map(Some),
+    };
+    match connected {
+        Ok(stream) => {
+            info!(%fallback, "Using the fallback target");
+            events.emit(ProxyEvent::FallbackUsed {
+                peer,
+                target: fallback.clone(),
+            });
+            Ok((stream, fallback))
+        }
+        Err(e) => {
+            report(&e, peer, events);
+            Err(e)
+        }
+    }
+}
+
+async fn connect_once(target: &ForwardTarget, options: &ListenerOptions) -> Result<TcpStream> {
+    let connect = TcpStream::connect((target.domain.as_str(), target.port));
+    let stream = match options.timeouts.connect_secs {
+        Some(secs) => tokio::time::timeout(Duration::from_secs(secs.into()), connect)
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "Connect timed out"))??,
+        None => connect.await?,
+    };
+    if let Err(e) = sockets::apply(&stream, &options.socket) {
+        warn!(upstream = %target, error = %e, "Failed to set socket options");
+    }
+    Ok(stream)
+}
+
+fn report(error: &Error, peer: SocketAddr, events: &EventSink) {
+    if error.kind() == ErrorKind::TimedOut {
+        events.emit(ProxyEvent::TimedOut {
+            peer,
+            timeout: TimeoutKind::Connect,
+        });
+    } else {
+        events.emit(ProxyEvent::UpstreamFailed {
+            peer,
+            error: error.to_string(),
+        });
+    }
+}
@@ -0,0 +1,72 @@
+#![cfg(unix)]
+
+mod common;
+
+use std::env;
+use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+use std::process::Command;
+
+use common::{eventually, http_get, target, Harness, Upstream};
+use dynamic_tcp_proxy::{ListenerOptions, ProxyConfig};
+
+/// Set in the process that is handed the socket, the port it listens on.
+const ACTIVATED_PORT: &str = "PORT_SWITCH_TEST_ACTIVATED_PORT";
+
+/// Runs `activated_child` in a process started the way systemd starts a socket activated
+/// service: the listening socket on descriptor 3 and `LISTEN_PID` set to its own pid.
+#[test]
+fn listens_on_activated_socket() {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Cannot bind");
+    let port = listener.local_addr().expect("No local address").port();
+    socket2::SockRef::from(&listener)
+        .set_cloexec(false)
+        .expect("Cannot pass the socket on");
+
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(
+            r#"exec 3<&"$SOCKET_FD"; LISTEN_PID=$$ LISTEN_FDS=1 LISTEN_FDNAMES=web exec "$0" "$@""#,
+        )
+        .arg(env::current_exe().expect("No test binary"))
+        .args(["--exact", "activated_child", "--nocapture"])
+        .env("SOCKET_FD", listener.as_raw_fd().to_string())
+        .env(ACTIVATED_PORT, port.to_string())
+        .output()
+        .expect("Cannot run the activated process");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // A filter matching nothing would pass as well
+    assert!(
+        output.status.success() && stdout.contains("1 passed"),
+        "Activated process failed:\n{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn activated_child() {
+    let Some(port) = env::var(ACTIVATED_PORT)
+        .ok()
+        .and_then(|port| port.parse().ok())
+    else {
+        return;
+    };
+    let upstream = Upstream::http("activated");
+    let harness = Harness::start();
+    let options = ListenerOptions {
+        activated_socket: Some("web".to_owned()),
+        ..Default::default()
+    };
+    let looping = ProxyConfig::forward(0, target(port), options.clone());
+    assert!(
+        looping.validate().is_err(),
+        "Forwarding to the activated socket"
+    );
+    // Activated sockets need no listening port of their own
+    harness.forward_with(0, upstream.target(), options);
+
+    eventually("the activated socket answers", || {
+        http_get(port).is_ok_and(|body| body == "activated")
+    });
+}
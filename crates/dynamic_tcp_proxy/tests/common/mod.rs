@@ -0,0 +1,227 @@
+//! Throwaway upstreams and a proxy driven the way the GUI drives it, each listening on
+//! ports picked by the system so that tests run side by side.
+
+#![allow(dead_code)]
+
+use std::io::{Read, Result, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use dynamic_tcp_proxy::{DynamicProxy, ForwardTarget, ListenerOptions, ProxyConfig};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Harness {
+    proxy: Option<DynamicProxy>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Harness {
+    pub fn start() -> Self {
+        let (proxy, handle) = DynamicProxy::initiate().expect("Cannot start the proxy");
+        Self {
+            proxy: Some(proxy),
+            handle: Some(handle),
+        }
+    }
+
+    pub fn proxy(&self) -> &DynamicProxy {
+        self.proxy.as_ref().expect("Proxy already stopped")
+    }
+
+    pub fn update(&self, config: ProxyConfig) {
+        config.validate().expect("Invalid config");
+        self.proxy().update(config).expect("Proxy stopped");
+    }
+
+    pub fn forward(&self, listen_port: u16, target: ForwardTarget) {
+        self.forward_with(listen_port, target, ListenerOptions::default());
+    }
+
+    pub fn forward_with(&self, listen_port: u16, target: ForwardTarget, options: ListenerOptions) {
+        self.update(ProxyConfig::forward(listen_port, target, options));
+    }
+
+    /// Starts forwarding to `target` on a port picked by the system, returned once bound.
+    pub fn listen(&self, target: ForwardTarget) -> u16 {
+        self.listen_with(target, ListenerOptions::default())
+    }
+
+    pub fn listen_with(&self, target: ForwardTarget, options: ListenerOptions) -> u16 {
+        self.forward_with(0, target, options);
+        eventually("the listener is up", || !self.ports().is_empty());
+        self.ports()[0]
+    }
+
+    /// Ports of the running listeners, in the order of the range.
+    pub fn ports(&self) -> Vec<u16> {
+        let listening = self.proxy().listening();
+        listening.iter().map(|addr| addr.port()).collect()
+    }
+
+    /// Stops listening, waiting for it so that the next `listen` cannot see the old ports.
+    pub fn turn_off(&self) {
+        self.update(ProxyConfig::default());
+        eventually("the listener is down", || self.ports().is_empty());
+    }
+}
+
+impl Drop for Harness {
+    /// Waits for the listeners to be closed so the next test starts from scratch.
+    fn drop(&mut self) {
+        drop(self.proxy.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+pub fn target(port: u16) -> ForwardTarget {
+    ForwardTarget {
+        domain: "localhost".to_owned(),
+        port,
+        ..Default::default()
+    }
+}
+
+/// Server on an ephemeral port answering each connection on its own thread.
+pub struct Upstream {
+    pub port: u16,
+}
+
+impl Upstream {
+    fn spawn(
+        listener: TcpListener,
+        handle: impl Fn(TcpStream) + Send + Sync + Clone + 'static,
+    ) -> Self {
+        let port = listener.local_addr().expect("No local address").port();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let handle = handle.clone();
+                thread::spawn(move || handle(stream));
+            }
+        });
+        Self { port }
+    }
+
+    /// Sends back everything it reads.
+    pub fn echo() -> Self {
+        Self::spawn(bind(), |mut stream| {
+            let mut reader = stream.try_clone().expect("Cannot clone stream");
+            let _ = std::io::copy(&mut reader, &mut stream);
+        })
+    }
+
+    /// Reads until the client is done sending, then keeps its side open without answering.
+    pub fn holding() -> Self {
+        Self::spawn(bind(), |mut stream| {
+            let _ = std::io::copy(&mut stream, &mut std::io::sink());
+            thread::sleep(TIMEOUT * 2);
+        })
+    }
+
+    /// Answers every request with `body`.
+    pub fn http(body: &'static str) -> Self {
+        Self::http_on(bind(), body)
+    }
+
+    /// Servers on consecutive ports, each answering with the body at the same offset.
+    pub fn http_range(bodies: &[&'static str]) -> Vec<Self> {
+        let listeners = loop {
+            if let Some(listeners) = bind_consecutive(bodies.len()) {
+                break listeners;
+            }
+        };
+        listeners
+            .into_iter()
+            .zip(bodies)
+            .map(|(listener, body)| Self::http_on(listener, body))
+            .collect()
+    }
+
+    fn http_on(listener: TcpListener, body: &'static str) -> Self {
+        Self::spawn(listener, move |mut stream| {
+            let mut head = Vec::new();
+            let mut buf = [0; 1024];
+            while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => return,
+                    Ok(read) => head.extend_from_slice(&buf[..read]),
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes());
+        })
+    }
+
+    pub fn target(&self) -> ForwardTarget {
+        target(self.port)
+    }
+}
+
+fn bind() -> TcpListener {
+    TcpListener::bind("127.0.0.1:0").expect("Cannot bind")
+}
+
+/// Listeners on `count` consecutive ports following one picked by the system, `None` when
+/// one of those is taken.
+fn bind_consecutive(count: usize) -> Option<Vec<TcpListener>> {
+    let first = bind();
+    let port = first.local_addr().expect("No local address").port();
+    let mut listeners = vec![first];
+    for offset in 1..count {
+        let port = port.checked_add(u16::try_from(offset).ok()?)?;
+        listeners.push(TcpListener::bind(("127.0.0.1", port)).ok()?);
+    }
+    Some(listeners)
+}
+
+pub fn connect(port: u16) -> Result<TcpStream> {
+    let stream = TcpStream::connect(("127.0.0.1", port))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Sends `data` through `port` and returns what came back once the other side closed.
+pub fn round_trip(port: u16, data: &[u8]) -> Result<Vec<u8>> {
+    let mut stream = connect(port)?;
+    let mut writer = stream.try_clone()?;
+    let data = data.to_vec();
+    // Written from another thread so large payloads cannot fill both directions at once
+    let written = thread::spawn(move || {
+        writer.write_all(&data)?;
+        writer.shutdown(Shutdown::Write)
+    });
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received)?;
+    written.join().expect("Writer panicked")?;
+    Ok(received)
+}
+
+/// Body of the response to `GET /` sent through `port`.
+pub fn http_get(port: u16) -> Result<String> {
+    let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    let response = String::from_utf8_lossy(&round_trip(port, request)?).into_owned();
+    Ok(response
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| body.to_owned())
+        .unwrap_or_default())
+}
+
+/// Retries `check` until it holds, updates being applied by the proxy in the background.
+pub fn eventually(what: &str, check: impl Fn() -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !check() {
+        assert!(
+            Instant::now() < deadline,
+            "Timed out waiting until {}",
+            what
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,426 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::Shutdown;
+
+use common::{connect, eventually, http_get, round_trip, target, Harness, Upstream};
+use dynamic_tcp_proxy::{
+    AccessLogOptions, AccessRules, CannedResponse, ConnectionLimits, ForwardTarget, ListenerMode,
+    ListenerOptions, ProxyConfig, Route, TargetKind, Timeouts,
+};
+
+#[test]
+fn forwards_bytes_both_ways() {
+    let upstream = Upstream::echo();
+    let harness = Harness::start();
+    let port = harness.listen(upstream.target());
+
+    let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    assert_eq!(round_trip(port, &data).unwrap(), data);
+}
+
+#[test]
+fn switching_targets_moves_new_connections() {
+    let (a, b) = (Upstream::http("a"), Upstream::http("b"));
+    let harness = Harness::start();
+
+    let port = harness.listen(a.target());
+    eventually("a answers", || http_get(port).is_ok_and(|body| body == "a"));
+    harness.forward(port, b.target());
+    eventually("b answers", || http_get(port).is_ok_and(|body| body == "b"));
+    harness.forward(port, a.target());
+    eventually("a answers again", || {
+        http_get(port).is_ok_and(|body| body == "a")
+    });
+}
+
+#[test]
+fn open_connections_survive_a_switch() {
+    let (echo, other) = (Upstream::echo(), Upstream::http("other"));
+    let harness = Harness::start();
+    let port = harness.listen(echo.target());
+
+    let mut stream = connect(port).unwrap();
+    let mut buf = [0; 6];
+    stream.write_all(b"before").unwrap();
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"before");
+
+    harness.forward(port, other.target());
+    eventually("new connections go to the other target", || {
+        http_get(port).is_ok_and(|body| body == "other")
+    });
+    stream.write_all(b"after!").unwrap();
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"after!");
+}
+
+#[test]
+fn turning_off_stops_listening() {
+    let upstream = Upstream::echo();
+    let harness = Harness::start();
+    let port = harness.listen(upstream.target());
+
+    harness.turn_off();
+    eventually("the listener is down", || connect(port).is_err());
+}
+
+#[test]
+fn rebinds_after_turning_off() {
+    let upstream = Upstream::http("up");
+    let harness = Harness::start();
+
+    let first = harness.listen(upstream.target());
+    eventually("the first port answers", || {
+        http_get(first).is_ok_and(|body| body == "up")
+    });
+
+    harness.turn_off();
+    eventually("the first port is closed", || connect(first).is_err());
+    let second = harness.listen(upstream.target());
+    eventually("the second port answers", || {
+        http_get(second).is_ok_and(|body| body == "up")
+    });
+
+    // The first port was released and can be bound again
+    harness.turn_off();
+    eventually("the second port is closed", || connect(second).is_err());
+    harness.forward(first, upstream.target());
+    eventually("the first port answers again", || {
+        http_get(first).is_ok_and(|body| body == "up")
+    });
+}
+
+#[test]
+fn forwards_each_port_of_a_range() {
+    let upstreams = Upstream::http_range(&["a", "b"]);
+    let harness = Harness::start();
+
+    let options = ListenerOptions {
+        port_count: 2,
+        ..Default::default()
+    };
+    harness.listen_with(upstreams[0].target(), options);
+    let ports = harness.ports();
+    assert_eq!(ports.len(), 2);
+    assert!(http_get(ports[0]).is_ok_and(|body| body == "a"));
+    assert!(http_get(ports[1]).is_ok_and(|body| body == "b"));
+}
+
+#[test]
+fn logs_bytes_of_connections_closed_by_a_timeout() {
+    let upstream = Upstream::echo();
+    let harness = Harness::start();
+    let directory = std::env::temp_dir().join(format!(
+        "port_switch_test_timeout_log_{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&directory).unwrap();
+
+    let options = ListenerOptions {
+        timeouts: Timeouts {
+            idle_secs: Some(1),
+            ..Default::default()
+        },
+        access_log: Some(AccessLogOptions::default()),
+        capture_dir: Some(directory.clone()),
+        ..Default::default()
+    };
+    let port = harness.listen_with(upstream.target(), options);
+
+    let mut stream = connect(port).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut echoed = [0; 5];
+    stream.read_exact(&mut echoed).unwrap();
+    // Left open until the proxy gives up on it
+    let mut rest = Vec::new();
+    let _ = stream.read_to_end(&mut rest);
+
+    // Named after the listening port as configured, 0 here
+    let log = directory.join("port_switch_0_access.log");
+    eventually("the connection is logged", || {
+        std::fs::read_to_string(&log)
+            .is_ok_and(|lines| lines.contains("from_client=5 from_server=5 close=\"idle timeout\""))
+    });
+    let _ = std::fs::remove_dir_all(directory);
+}
+
+#[test]
+fn closes_clients_that_never_finish_their_handshake() {
+    let upstream = Upstream::http("never reached");
+    let harness = Harness::start();
+    let options = ListenerOptions {
+        mode: ListenerMode::Http,
+        timeouts: Timeouts {
+            connect_secs: Some(1),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let port = harness.listen_with(upstream.target(), options);
+
+    let mut stream = connect(port).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+    let mut received = Vec::new();
+    stream
+        .read_to_end(&mut received)
+        .expect("Connection still open");
+    assert!(received.is_empty());
+}
+
+#[test]
+fn half_closed_connections_idle_out() {
+    let upstream = Upstream::holding();
+    let harness = Harness::start();
+    let options = ListenerOptions {
+        timeouts: Timeouts {
+            idle_secs: Some(1),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let port = harness.listen_with(upstream.target(), options);
+
+    let mut stream = connect(port).unwrap();
+    stream.write_all(b"done").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut received = Vec::new();
+    stream
+        .read_to_end(&mut received)
+        .expect("Connection still open");
+}
+
+#[test]
+fn blackholed_connections_idle_out() {
+    let harness = Harness::start();
+    let blackhole = ForwardTarget {
+        kind: TargetKind::Blackhole,
+        ..Default::default()
+    };
+    let options = ListenerOptions {
+        timeouts: Timeouts {
+            idle_secs: Some(1),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let port = harness.listen_with(blackhole, options);
+
+    let mut stream = connect(port).unwrap();
+    stream.write_all(b"anyone there?").unwrap();
+    let mut received = Vec::new();
+    stream
+        .read_to_end(&mut received)
+        .expect("Connection still open");
+    assert!(received.is_empty());
+}
+
+#[test]
+fn access_rules_match_the_client_of_the_proxy_header() {
+    let upstream = Upstream::http("allowed");
+    let harness = Harness::start();
+    let options = ListenerOptions {
+        accept_proxy_protocol: true,
+        access: AccessRules {
+            allow: vec!["192.0.2.0/24".parse().unwrap()],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let port = harness.listen_with(upstream.target(), options);
+
+    let get_from = |client: &str| {
+        let request = format!(
+            "PROXY TCP4 {client} 127.0.0.1 40000 {port}\r\n\
+             GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+        );
+        round_trip(port, request.as_bytes())
+            .map(|response| String::from_utf8_lossy(&response).into_owned())
+    };
+    assert!(get_from("192.0.2.10").unwrap().ends_with("allowed"));
+    assert!(get_from("198.51.100.10").map_or(true, |response| response.is_empty()));
+}
+
+#[test]
+fn clients_keep_their_target_after_a_switch() {
+    let (a, b) = (Upstream::http("a"), Upstream::http("b"));
+    let harness = Harness::start();
+    let options = |routed: &[&Upstream]| ListenerOptions {
+        accept_proxy_protocol: true,
+        affinity_secs: Some(60),
+        routes: routed
+            .iter()
+            .map(|upstream| Route {
+                name: upstream.port.to_string(),
+                target: upstream.target(),
+            })
+            .collect(),
+        ..Default::default()
+    };
+    let port = harness.listen_with(a.target(), options(&[&a, &b]));
+    let get_from = |client: &str| {
+        let request = format!(
+            "PROXY TCP4 {client} 127.0.0.1 40000 {port}\r\n\
+             GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+        );
+        let response = round_trip(port, request.as_bytes()).unwrap_or_default();
+        let response = String::from_utf8_lossy(&response).into_owned();
+        response
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body.to_owned())
+            .unwrap_or_default()
+    };
+
+    eventually("a answers", || get_from("192.0.2.1") == "a");
+    harness.forward_with(port, b.target(), options(&[&a, &b]));
+    // A client seen before the switch would be pinned to a, each try comes from a new one
+    let next_client = std::cell::Cell::new(2);
+    eventually("b answers new clients", || {
+        next_client.set(next_client.get() + 1);
+        get_from(&format!("192.0.2.{}", next_client.get())) == "b"
+    });
+    assert_eq!(get_from("192.0.2.1"), "a");
+
+    harness.forward_with(port, b.target(), options(&[&b]));
+    eventually("a is no longer routed to", || get_from("192.0.2.1") == "b");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn spliced_connections_can_be_inspected() {
+    let upstream = Upstream::echo();
+    let harness = Harness::start();
+    let options = ListenerOptions {
+        zero_copy: true,
+        ..Default::default()
+    };
+    let port = harness.listen_with(upstream.target(), options);
+
+    let stream = connect(port).unwrap();
+    let client = stream.local_addr().unwrap();
+    let echo = |data: &[u8]| {
+        let mut buf = vec![0; data.len()];
+        (&stream).write_all(data).unwrap();
+        (&stream).read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    };
+    echo(b"spliced");
+    let id = harness
+        .proxy()
+        .connections()
+        .into_iter()
+        .find(|connection| connection.peer == client)
+        .expect("Spliced connection listed")
+        .id;
+
+    let inspector = harness.proxy().inspect(id).unwrap();
+    eventually("the inspector sees the bytes", || {
+        echo(b"copied");
+        inspector.chunks().any(|chunk| chunk.data == b"copied")
+    });
+}
+
+#[test]
+fn canned_responses_keep_their_own_framing() {
+    let harness = Harness::start();
+    let response = CannedResponse {
+        status: 200,
+        headers: vec![
+            ("Content-Length".to_owned(), "999".to_owned()),
+            ("X-Injected".to_owned(), "a\r\nSet-Cookie: b".to_owned()),
+            ("X-Kept".to_owned(), "yes".to_owned()),
+        ],
+        body: "canned".to_owned(),
+    };
+    let canned = ForwardTarget {
+        kind: TargetKind::Respond(response),
+        ..Default::default()
+    };
+    let port = harness.listen(canned);
+
+    let request = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let response = String::from_utf8(round_trip(port, request).unwrap()).unwrap();
+    assert!(response.contains("Content-Length: 6\r\n"));
+    assert!(!response.contains("999") && !response.contains("Set-Cookie"));
+    assert!(response.contains("X-Kept: yes\r\n") && response.ends_with("canned"));
+}
+
+#[test]
+fn rejects_invalid_configs() {
+    let options = |port_count| ListenerOptions {
+        port_count,
+        ..Default::default()
+    };
+
+    let to_itself = ProxyConfig::forward(8080, target(8080), ListenerOptions::default());
+    assert_eq!(
+        to_itself.validate(),
+        Err("Cannot forward to listening port".to_owned())
+    );
+
+    let into_range = ProxyConfig::forward(8080, target(8082), options(3));
+    assert_eq!(
+        into_range.validate(),
+        Err("Cannot forward to listening port".to_owned())
+    );
+
+    let past_last_port = ProxyConfig::forward(65_535, target(3000), options(2));
+    assert_eq!(
+        past_last_port.validate(),
+        Err("Listening range goes past the last port".to_owned())
+    );
+
+    let target_past_last_port = ProxyConfig::forward(3000, target(65_534), options(3));
+    assert_eq!(
+        target_past_last_port.validate(),
+        Err("Target range goes past the last port".to_owned())
+    );
+    let fallback_past_last_port = ProxyConfig::forward(
+        3000,
+        target(4000),
+        ListenerOptions {
+            port_count: 2,
+            fallback: Some(target(65_535)),
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        fallback_past_last_port.validate(),
+        Err("Target range goes past the last port".to_owned())
+    );
+
+    let fallback_to_itself = ProxyConfig::forward(
+        8080,
+        target(3000),
+        ListenerOptions {
+            fallback: Some(target(8080)),
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        fallback_to_itself.validate(),
+        Err("Cannot fall back to listening port".to_owned())
+    );
+
+    let no_connections = ProxyConfig::forward(
+        8080,
+        target(3000),
+        ListenerOptions {
+            limits: ConnectionLimits {
+                max_connections: Some(0),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        no_connections.validate(),
+        Err("Connection limits must allow at least one connection".to_owned())
+    );
+
+    let next_to_range = ProxyConfig::forward(8080, target(8083), options(3));
+    assert_eq!(next_to_range.validate(), Ok(()));
+    let up_to_last_port = ProxyConfig::forward(3000, target(65_533), options(3));
+    assert_eq!(up_to_last_port.validate(), Ok(()));
+    assert_eq!(ProxyConfig::default().validate(), Ok(()));
+}
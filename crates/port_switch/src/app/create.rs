@@ -1,5 +1,6 @@
 use super::{App, Pages};
-use egui::vec2;
+use dynamic_tcp_proxy::{CannedResponse, DirectoryTarget, TargetKind};
+use egui::{vec2, Ui};
 
 impl App {
     pub(super) fn creation_page(&mut self, ctx: &egui::Context) {
@@ -22,7 +23,7 @@ impl App {
                         let mut replace_on_save: Option<usize> = None;
 
                         let editing_port = match &mut self.active_page {
-                            Pages::List => return,
+                            Pages::List | Pages::Listener | Pages::Inspector => return,
                             Pages::Creation(new_for) => new_for,
                             Pages::Edit(pos, edit_for) => {
                                 replace_on_save = Some(*pos);
@@ -33,21 +34,45 @@ impl App {
                         ui.label("Name: ");
                         ui.text_edit_singleline(&mut editing_port.name);
                         ui.end_row();
-                        ui.label("Domain: ");
-                        ui.text_edit_singleline(&mut editing_port.target.domain);
-                        ui.end_row();
-                        ui.label("Port: ");
-                        ui.add(
-                            egui::DragValue::new(&mut editing_port.target.port).range(0..=65535),
-                        );
+                        ui.label("Kind: ");
+                        kind_picker(ui, &mut editing_port.target.kind);
                         ui.end_row();
 
+                        match &mut editing_port.target.kind {
+                            TargetKind::Address => {
+                                ui.label("Domain: ");
+                                ui.text_edit_singleline(&mut editing_port.target.domain);
+                                ui.end_row();
+                                ui.label("Port: ");
+                                ui.add(
+                                    egui::DragValue::new(&mut editing_port.target.port)
+                                        .range(0..=65535),
+                                );
+                                ui.end_row();
+                            }
+                            TargetKind::Respond(response) => canned_response_form(ui, response),
+                            TargetKind::Directory(directory) => directory_form(ui, directory),
+                            TargetKind::Unavailable
+                            | TargetKind::Reject
+                            | TargetKind::Blackhole => {}
+                        }
+
                         if let Some(err_msg) = &editing_port.error {
                             ui.label(err_msg);
                             ui.end_row();
                         }
 
                         if ui.button("Save").clicked() {
+                            if let TargetKind::Respond(response) = &editing_port.target.kind {
+                                let broken = response.headers.iter().any(|(name, value)| {
+                                    name.contains(['\r', '\n']) || value.contains(['\r', '\n'])
+                                });
+                                if broken {
+                                    editing_port.error =
+                                        Some("Headers cannot contain line breaks".to_string());
+                                    return;
+                                }
+                            }
                             let mut new_port = editing_port.clone();
                             new_port.error = None;
                             let new_port = new_port;
@@ -76,6 +101,7 @@ impl App {
                                 }
                             }
                             self.active_page = Pages::List;
+                            self.update_backend();
                         }
                         ui.end_row();
                     });
@@ -83,3 +109,84 @@ impl App {
         });
     }
 }
+
+pub(super) fn kind_name(kind: &TargetKind) -> &'static str {
+    match kind {
+        TargetKind::Address => "Address",
+        TargetKind::Respond(_) => "Canned response",
+        TargetKind::Unavailable => "Unavailable page",
+        TargetKind::Directory(_) => "Directory",
+        TargetKind::Reject => "Resets connections",
+        TargetKind::Blackhole => "Never answers",
+    }
+}
+
+fn kind_picker(ui: &mut Ui, kind: &mut TargetKind) {
+    let choices = [
+        TargetKind::Address,
+        TargetKind::Respond(CannedResponse::default()),
+        TargetKind::Unavailable,
+        TargetKind::Directory(DirectoryTarget::default()),
+    ];
+    egui::ComboBox::from_id_source("target_kind")
+        .selected_text(kind_name(kind))
+        .show_ui(ui, |ui| {
+            for choice in choices {
+                let selected = std::mem::discriminant(kind) == std::mem::discriminant(&choice);
+                if ui.selectable_label(selected, kind_name(&choice)).clicked() && !selected {
+                    *kind = choice;
+                }
+            }
+        });
+}
+
+fn canned_response_form(ui: &mut Ui, response: &mut CannedResponse) {
+    ui.label("Status: ");
+    ui.add(egui::DragValue::new(&mut response.status).range(100..=599));
+    ui.end_row();
+
+    ui.label("Headers: ");
+    ui.vertical(|ui| {
+        let mut removed = None;
+        for (index, (name, value)) in response.headers.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(name).desired_width(120.0));
+                ui.add(egui::TextEdit::singleline(value).desired_width(180.0));
+                if ui.small_button("x").clicked() {
+                    removed = Some(index);
+                }
+            });
+        }
+        if let Some(index) = removed {
+            response.headers.remove(index);
+        }
+        if ui.button("Add header").clicked() {
+            response.headers.push(Default::default());
+        }
+    });
+    ui.end_row();
+
+    ui.label("Body: ");
+    ui.add(egui::TextEdit::multiline(&mut response.body).code_editor());
+    ui.end_row();
+}
+
+fn directory_form(ui: &mut Ui, directory: &mut DirectoryTarget) {
+    ui.label("Folder: ");
+    let mut root = directory.root.display().to_string();
+    if ui.text_edit_singleline(&mut root).changed() {
+        directory.root = root.into();
+    }
+    ui.end_row();
+
+    ui.label("Index file: ");
+    ui.text_edit_singleline(&mut directory.index);
+    ui.end_row();
+
+    ui.label("SPA fallback: ");
+    ui.checkbox(
+        &mut directory.spa_fallback,
+        "Serve the index for unknown routes",
+    );
+    ui.end_row();
+}
@@ -0,0 +1,146 @@
+use dynamic_tcp_proxy::{Direction, Inspector};
+use egui::{Color32, RichText};
+
+use super::{App, Pages};
+
+const MAX_ROWS: usize = 10_000;
+const CLIENT_COLOR: Color32 = Color32::from_rgb(90, 150, 240);
+const SERVER_COLOR: Color32 = Color32::from_rgb(90, 180, 110);
+
+#[derive(Default)]
+pub(super) struct Inspection {
+    connection_id: Option<u64>,
+    inspector: Option<Inspector>,
+    rows: Vec<(Direction, String)>,
+    offsets: [usize; 2],
+    as_text: bool,
+}
+
+impl Inspection {
+    fn select(&mut self, connection_id: u64, inspector: Option<Inspector>) {
+        *self = Inspection {
+            connection_id: Some(connection_id),
+            inspector,
+            as_text: self.as_text,
+            ..Default::default()
+        };
+    }
+
+    fn poll(&mut self) {
+        let Some(inspector) = &self.inspector else {
+            return;
+        };
+        for chunk in inspector.chunks() {
+            let side = chunk.direction as usize;
+            if self.as_text {
+                for line in String::from_utf8_lossy(&chunk.data).lines() {
+                    self.rows.push((chunk.direction, line.to_owned()));
+                }
+            } else {
+                for (row, bytes) in chunk.data.chunks(16).enumerate() {
+                    let offset = self.offsets[side] + row * 16;
+                    self.rows.push((chunk.direction, hex_row(offset, bytes)));
+                }
+            }
+            self.offsets[side] += chunk.data.len();
+        }
+        if self.rows.len() > MAX_ROWS {
+            self.rows.drain(..self.rows.len() - MAX_ROWS);
+        }
+    }
+}
+
+fn hex_row(offset: usize, bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let ascii: String = bytes
+        .iter()
+        .map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        })
+        .collect();
+    format!("{:08x}  {:<47}  {}", offset, hex.join(" "), ascii)
+}
+
+impl App {
+    pub(super) fn inspector_page(&mut self, ctx: &egui::Context) {
+        self.inspection.poll();
+
+        egui::TopBottomPanel::top("inspector_top_panel").show(ctx, |ui| {
+            egui::menu::bar(ui, |ui| {
+                egui::Frame::none().outer_margin(10.0).show(ui, |ui| {
+                    if ui.button("Back").clicked() {
+                        self.inspection = Inspection::default();
+                        self.active_page = Pages::List;
+                    }
+                    ui.separator();
+                    let mut as_text = self.inspection.as_text;
+                    ui.radio_value(&mut as_text, false, "Hex");
+                    ui.radio_value(&mut as_text, true, "Text");
+                    if as_text != self.inspection.as_text {
+                        self.inspection.as_text = as_text;
+                        self.inspection.rows.clear();
+                    }
+                    ui.separator();
+                    ui.label(RichText::new("client").color(CLIENT_COLOR));
+                    ui.label(RichText::new("server").color(SERVER_COLOR));
+                })
+            });
+        });
+
+        egui::SidePanel::left("connections_panel").show(ctx, |ui| {
+            ui.heading("Connections");
+            ui.add_space(10.0);
+            let connections = match &self.proxy_handle {
+                Some(backend) => backend.connections(),
+                None => Vec::new(),
+            };
+            if connections.is_empty() {
+                ui.label("No open connections");
+            }
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for connection in connections {
+                    let selected = self.inspection.connection_id == Some(connection.id);
+                    let label = format!(
+                        "#{} {} → {}:{}",
+                        connection.id,
+                        connection.peer,
+                        connection.target.domain,
+                        connection.target.port
+                    );
+                    if ui.selectable_label(selected, label).clicked() && !selected {
+                        let inspector = self
+                            .proxy_handle
+                            .as_ref()
+                            .and_then(|backend| backend.inspect(connection.id));
+                        self.inspection.select(connection.id, inspector);
+                    }
+                }
+            });
+        });
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+            let rows = &self.inspection.rows;
+            egui::ScrollArea::both().stick_to_bottom(true).show_rows(
+                ui,
+                row_height,
+                rows.len(),
+                |ui, range| {
+                    for (direction, row) in &rows[range] {
+                        let color = match direction {
+                            Direction::ClientToServer => CLIENT_COLOR,
+                            Direction::ServerToClient => SERVER_COLOR,
+                        };
+                        ui.label(RichText::new(row).monospace().color(color));
+                    }
+                },
+            );
+        });
+
+        ctx.request_repaint_after(std::time::Duration::from_millis(100));
+    }
+}
@@ -1,5 +1,7 @@
+use dynamic_tcp_proxy::{FaultRules, NetworkProfile};
 use egui::{warn_if_debug_build, Align, Margin, RichText, Ui};
 
+use super::create::kind_name;
 use super::{App, ForwardPort, Pages};
 use crate::widgets::Toggle;
 
@@ -17,6 +19,9 @@ impl App {
                             ctx.send_viewport_cmd(egui::ViewportCommand::Close);
                         }
                     });
+                    if ui.button("Inspector").clicked() {
+                        self.active_page = Pages::Inspector;
+                    }
                     ui.with_layout(egui::Layout::right_to_left(Align::Center), |ui| {
                         egui::widgets::global_dark_light_mode_switch(ui);
                         if cfg!(debug_assertions) && ui.button("Reset").clicked() {
@@ -34,6 +39,7 @@ impl App {
 
             egui::Frame::default().inner_margin(10.0).show(ui, |ui| {
                 ui.heading("From");
+                let port_count = self.listener_options.port_count;
 
                 ui.horizontal(|ui| {
                     ui.label("Port: ");
@@ -41,13 +47,58 @@ impl App {
                         !self.is_enabled,
                         egui::DragValue::new(&mut self.listen_port).range(0..=65535),
                     );
+                    if port_count > 1 {
+                        let last = self.listen_port.saturating_add(port_count - 1);
+                        ui.label(format!("to {}", last));
+                    }
 
                     ui.with_layout(egui::Layout::right_to_left(Align::Center), |ui| {
                         if ui.add(Toggle::new(&mut self.is_enabled)).clicked() {
                             self.update_backend();
                         };
+                        if ui.button("Settings").clicked() {
+                            self.active_page = Pages::Listener;
+                        };
+                    });
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Network: ");
+                    if network_picker(ui, &mut self.listener_options.network) {
+                        self.update_backend();
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Record: ");
+                    ui.with_layout(egui::Layout::right_to_left(Align::Center), |ui| {
+                        if ui
+                            .add(Toggle::new(&mut self.listener_options.record))
+                            .clicked()
+                        {
+                            self.update_backend();
+                        };
                     });
                 });
+                ui.horizontal(|ui| {
+                    ui.label("Record sessions: ");
+                    ui.with_layout(egui::Layout::right_to_left(Align::Center), |ui| {
+                        if ui
+                            .add(Toggle::new(&mut self.listener_options.record_sessions))
+                            .clicked()
+                        {
+                            self.update_backend();
+                        };
+                    });
+                });
+                if let (true, Some(backend)) = (self.is_enabled, &self.proxy_handle) {
+                    let stats = backend.stats();
+                    ui.label(
+                        RichText::new(format!(
+                            "{} accepted, {} denied, {} rejected",
+                            stats.accepted, stats.denied, stats.rejected
+                        ))
+                        .small(),
+                    );
+                }
                 ui.add_space(10.0);
 
                 ui.separator();
@@ -62,7 +113,15 @@ impl App {
                         ui.heading("To");
                     });
 
-                for (index, forward_port) in self.forward_ports.clone().iter().enumerate() {
+                let configured = self
+                    .forward_ports
+                    .clone()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, port)| (Some(index), port));
+                let builtins = ForwardPort::builtins().into_iter().map(|port| (None, port));
+                for (index, forward_port) in configured.chain(builtins) {
+                    let forward_port = &forward_port;
                     let mut is_active = if let Some(cur_port) = &self.active_forward_port {
                         cur_port == forward_port
                     } else {
@@ -71,6 +130,10 @@ impl App {
                     ui.horizontal(|ui| {
                         ui.label(&forward_port.name);
                         ui.with_layout(egui::Layout::right_to_left(Align::Center), |ui| {
+                            // Built-in targets cannot be edited or removed
+                            let Some(index) = index else {
+                                return;
+                            };
                             if ui
                                 .add_enabled(!is_active, egui::Button::new("Edit"))
                                 .clicked()
@@ -79,15 +142,24 @@ impl App {
                             };
                             if ui.add_enabled(!is_active, egui::Button::new("x")).clicked() {
                                 self.forward_ports.retain(|port| port != forward_port);
+                                self.update_backend();
                             };
                         });
                     });
                     ui.horizontal(|ui| {
-                        ui.label("Port: ");
-                        ui.label(format!("{}", forward_port.target.port));
-                        if forward_port.target.is_external() {
-                            ui.label("Domain: ");
-                            ui.label(format!("({})", forward_port.target.domain));
+                        if forward_port.target.is_local() {
+                            ui.label(kind_name(&forward_port.target.kind));
+                        } else {
+                            ui.label("Port: ");
+                            ui.label(format!("{}", forward_port.target.port));
+                            if port_count > 1 {
+                                let last = forward_port.target.port.saturating_add(port_count - 1);
+                                ui.label(format!("to {}", last));
+                            }
+                            if forward_port.target.is_external() {
+                                ui.label("Domain: ");
+                                ui.label(format!("({})", forward_port.target.domain));
+                            }
                         }
 
                         ui.with_layout(egui::Layout::right_to_left(Align::Center), |ui| {
@@ -111,6 +183,26 @@ impl App {
                     self.active_page = Pages::Creation(ForwardPort::default());
                 }
 
+                ui.add_space(10.0);
+                egui::CollapsingHeader::new("Faults").show(ui, |ui| {
+                    if fault_toggles(ui, &mut self.listener_options.faults) {
+                        self.update_backend();
+                    }
+                });
+
+                egui::CollapsingHeader::new(format!("Events ({})", self.events.len())).show(
+                    ui,
+                    |ui| {
+                        egui::ScrollArea::vertical()
+                            .max_height(150.0)
+                            .show(ui, |ui| {
+                                for event in self.events.iter().rev() {
+                                    ui.label(RichText::new(event.to_string()).small());
+                                }
+                            });
+                    },
+                );
+
                 warn_if_debug_build(ui)
             });
         });
@@ -128,3 +220,56 @@ fn error_warning(ui: &mut Ui, error: &Option<String>) {
         });
     }
 }
+
+fn network_picker(ui: &mut Ui, network: &mut Option<NetworkProfile>) -> bool {
+    let selected = network
+        .as_ref()
+        .map_or("Unthrottled".to_owned(), |profile| profile.name.clone());
+    let mut changed = false;
+
+    egui::ComboBox::from_id_source("network_profile")
+        .selected_text(selected)
+        .show_ui(ui, |ui| {
+            changed |= ui.selectable_value(network, None, "Unthrottled").changed();
+            for preset in NetworkProfile::presets() {
+                let name = preset.name.clone();
+                changed |= ui.selectable_value(network, Some(preset), name).changed();
+            }
+        });
+    changed
+}
+
+fn fault_toggles(ui: &mut Ui, faults: &mut FaultRules) -> bool {
+    let mut changed = false;
+    let mut fault_row = |ui: &mut Ui, label: String, enabled: &mut bool| {
+        ui.horizontal(|ui| {
+            ui.label(label);
+            ui.with_layout(egui::Layout::right_to_left(Align::Center), |ui| {
+                changed |= ui.add(Toggle::new(enabled)).clicked();
+            });
+        });
+    };
+
+    fault_row(
+        ui,
+        format!("Refuse {}% of connections", faults.refuse.value),
+        &mut faults.refuse.enabled,
+    );
+    fault_row(
+        ui,
+        format!("Reset after {} bytes", faults.reset_after_bytes.value),
+        &mut faults.reset_after_bytes.enabled,
+    );
+    fault_row(
+        ui,
+        format!("Reset after {} seconds", faults.reset_after_secs.value),
+        &mut faults.reset_after_secs.enabled,
+    );
+    fault_row(ui, "Stall reads".to_owned(), &mut faults.stall);
+    fault_row(
+        ui,
+        format!("Corrupt {}% of bytes", faults.corrupt.value),
+        &mut faults.corrupt.enabled,
+    );
+    changed
+}
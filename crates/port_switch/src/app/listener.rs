@@ -0,0 +1,690 @@
+use super::{App, Pages};
+use crate::logging::{self, LogLevel};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use dynamic_tcp_proxy::{
+    AccessLogOptions, Credentials, IpNet, Keepalive, LimitAction, ListenerMode, Route,
+    UnmatchedAction,
+};
+use egui::emath::Numeric;
+use egui::{vec2, RichText, Ui};
+
+/// Text being typed for new access rules, kept until it parses.
+#[derive(Default)]
+pub(super) struct AccessInput {
+    allow: String,
+    deny: String,
+}
+
+impl App {
+    pub(super) fn listener_page(&mut self, ctx: &egui::Context) {
+        egui::TopBottomPanel::top("listener_top_panel").show(ctx, |ui| {
+            egui::menu::bar(ui, |ui| {
+                egui::Frame::none().outer_margin(10.0).show(ui, |ui| {
+                    if ui.button("Back").clicked() {
+                        self.active_page = Pages::List;
+                    }
+                })
+            });
+        });
+        egui::CentralPanel::default().show(ctx, |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                egui::Frame::default().inner_margin(10.0).show(ui, |ui| {
+                    let mut changed = false;
+
+                    egui::Grid::new("listener_form")
+                        .min_col_width(100.0)
+                        .num_columns(2)
+                        .spacing(vec2(0.0, 10.0))
+                        .show(ui, |ui| {
+                            let options = &mut self.listener_options;
+
+                            ui.label("Mode: ");
+                            ui.horizontal(|ui| {
+                                changed |= ui
+                                    .radio_value(&mut options.mode, ListenerMode::Forward, "Forward")
+                                    .changed();
+                                changed |= ui
+                                    .radio_value(&mut options.mode, ListenerMode::Socks5, "SOCKS5")
+                                    .changed();
+                                changed |= ui
+                                    .radio_value(
+                                        &mut options.mode,
+                                        ListenerMode::HttpConnect,
+                                        "HTTP CONNECT",
+                                    )
+                                    .changed();
+                                changed |= ui
+                                    .radio_value(&mut options.mode, ListenerMode::Http, "HTTP")
+                                    .on_hover_text(
+                                        "Requests pick a port by name with the X-Port-Switch header, \
+                                         the __ps cookie or ?__ps=<name>",
+                                    )
+                                    .changed();
+                            });
+                            ui.end_row();
+
+                            if matches!(
+                                options.mode,
+                                ListenerMode::Socks5 | ListenerMode::HttpConnect
+                            ) {
+                                ui.label("Unmatched: ");
+                                ui.horizontal(|ui| {
+                                    changed |= ui
+                                        .radio_value(
+                                            &mut options.unmatched,
+                                            UnmatchedAction::Direct,
+                                            "Connect directly",
+                                        )
+                                        .on_hover_text("Destinations without an override or port are connected as requested")
+                                        .changed();
+                                    changed |= ui
+                                        .radio_value(
+                                            &mut options.unmatched,
+                                            UnmatchedAction::Reject,
+                                            "Reject",
+                                        )
+                                        .changed();
+                                });
+                                ui.end_row();
+                            }
+
+                            if options.mode == ListenerMode::Socks5 {
+                                ui.label("Authentication: ");
+                                let mut required = options.socks_credentials.is_some();
+                                if ui.checkbox(&mut required, "Username and password").changed() {
+                                    options.socks_credentials =
+                                        required.then(Credentials::default);
+                                    changed = true;
+                                }
+                                ui.end_row();
+                                if let Some(credentials) = &mut options.socks_credentials {
+                                    ui.label("Username: ");
+                                    changed |= ui
+                                        .text_edit_singleline(&mut credentials.username)
+                                        .changed();
+                                    ui.end_row();
+                                    ui.label("Password: ");
+                                    changed |= ui
+                                        .add(
+                                            egui::TextEdit::singleline(&mut credentials.password)
+                                                .password(true),
+                                        )
+                                        .changed();
+                                    ui.end_row();
+                                }
+                            }
+
+                            ui.label("Ports: ");
+                            changed |= ui
+                                .add_enabled(
+                                    !self.is_enabled,
+                                    egui::DragValue::new(&mut options.port_count)
+                                        .range(1..=1000)
+                                        .suffix(" consecutive"),
+                                )
+                                .on_hover_text("Each port forwards to the same offset from the target port")
+                                .changed();
+                            ui.end_row();
+
+                            if cfg!(unix) {
+                                ui.label("Activated socket: ");
+                                let mut name = options.activated_socket.clone().unwrap_or_default();
+                                let response = ui
+                                    .add_enabled(
+                                        !self.is_enabled,
+                                        egui::TextEdit::singleline(&mut name).hint_text("Bind ports"),
+                                    )
+                                    .on_hover_text("Name of the sockets passed by systemd, see FileDescriptorName=");
+                                if response.changed() {
+                                    options.activated_socket = (!name.is_empty()).then_some(name);
+                                    changed = true;
+                                }
+                                ui.end_row();
+                            }
+
+                            ui.label("PROXY protocol: ");
+                            changed |= ui
+                                .checkbox(&mut options.accept_proxy_protocol, "Accept v1/v2 header")
+                                .changed();
+                            ui.end_row();
+
+                            if cfg!(target_os = "linux") {
+                                ui.label("Zero copy: ");
+                                changed |= ui
+                                    .checkbox(&mut options.zero_copy, "Splice plain connections")
+                                    .on_hover_text("Connections that are shaped, faulted, idle timed, captured or recorded are copied as usual, spliced ones move over to copying when inspected")
+                                    .changed();
+                                ui.end_row();
+                            }
+
+                            ui.label("Capture folder: ");
+                            let mut capture_dir = options
+                                .capture_dir
+                                .as_ref()
+                                .map(|dir| dir.display().to_string())
+                                .unwrap_or_default();
+                            let response = ui.add(
+                                egui::TextEdit::singleline(&mut capture_dir)
+                                    .hint_text(std::env::temp_dir().display().to_string()),
+                            );
+                            if response.changed() {
+                                options.capture_dir =
+                                    (!capture_dir.is_empty()).then(|| capture_dir.into());
+                            }
+                            // Applied once typed so a running capture does not restart per key
+                            changed |= response.lost_focus();
+                            ui.end_row();
+
+                            ui.label("Access log: ");
+                            let mut enabled = options.access_log.is_some();
+                            if ui
+                                .checkbox(&mut enabled, "A line per closed connection")
+                                .on_hover_text("Written to the capture folder")
+                                .changed()
+                            {
+                                options.access_log = enabled.then(AccessLogOptions::default);
+                                changed = true;
+                            }
+                            ui.end_row();
+                            if let Some(access_log) = &mut options.access_log {
+                                ui.label("Rotate at MiB: ");
+                                let mut mebibytes = access_log.max_bytes / (1024 * 1024);
+                                if ui
+                                    .add(egui::DragValue::new(&mut mebibytes).range(1..=10_240))
+                                    .changed()
+                                {
+                                    access_log.max_bytes = mebibytes * 1024 * 1024;
+                                    changed = true;
+                                }
+                                ui.end_row();
+                                ui.label("Keep files: ");
+                                changed |= ui
+                                    .add(egui::DragValue::new(&mut access_log.keep).range(0..=100))
+                                    .changed();
+                                ui.end_row();
+                            }
+
+                            ui.label("Metrics port: ");
+                            changed |= optional_value(ui, &mut options.metrics_port, 9464);
+                            ui.end_row();
+
+                            ui.label("Log level: ");
+                            let log_level = self.log_level;
+                            egui::ComboBox::from_id_source("log_level")
+                                .selected_text(format!("{:?}", log_level))
+                                .show_ui(ui, |ui| {
+                                    for level in LogLevel::ALL {
+                                        ui.selectable_value(
+                                            &mut self.log_level,
+                                            level,
+                                            format!("{:?}", level),
+                                        );
+                                    }
+                                });
+                            if self.log_level != log_level {
+                                logging::set_level(self.log_level);
+                            }
+                            ui.end_row();
+                        });
+
+                    if matches!(
+                        self.listener_options.mode,
+                        ListenerMode::Socks5 | ListenerMode::HttpConnect
+                    ) {
+                        ui.add_space(10.0);
+                        ui.heading("Overrides");
+                        ui.label("Destinations sent to a port, matched as host or host:port");
+                        ui.add_space(10.0);
+                        changed |= self.override_table(ui);
+                    }
+
+                    ui.add_space(10.0);
+                    ui.heading("Access");
+                    ui.label("Deny wins, an empty allow list lets every client in. Clients behind a PROXY header are matched by the address it carries");
+                    ui.add_space(10.0);
+
+                    egui::Grid::new("access_form")
+                        .min_col_width(100.0)
+                        .num_columns(2)
+                        .spacing(vec2(0.0, 10.0))
+                        .show(ui, |ui| {
+                            let bind_address = &mut self.listener_options.bind_address;
+                            let choices = [
+                                (None, "Loopback only".to_owned()),
+                                (Some(Ipv4Addr::UNSPECIFIED.into()), "All IPv4 interfaces".to_owned()),
+                                (Some(Ipv6Addr::UNSPECIFIED.into()), "All interfaces".to_owned()),
+                            ];
+                            let selected = choices
+                                .iter()
+                                .find(|(address, _)| address == bind_address)
+                                .map_or_else(
+                                    || bind_address.map(|address| address.to_string()).unwrap_or_default(),
+                                    |(_, label)| label.clone(),
+                                );
+                            ui.label("Listen on: ");
+                            ui.add_enabled_ui(!self.is_enabled, |ui| {
+                                egui::ComboBox::from_id_source("bind_address")
+                                    .selected_text(selected)
+                                    .show_ui(ui, |ui| {
+                                        for (address, label) in choices {
+                                            changed |= ui
+                                                .selectable_value(bind_address, address, label)
+                                                .changed();
+                                        }
+                                    })
+                                    .response
+                                    .on_hover_text("Clients on other hosts can only connect when listening on their interface");
+                            });
+                            ui.end_row();
+
+                            let access = &mut self.listener_options.access;
+
+                            ui.label("Allow: ");
+                            changed |=
+                                network_list(ui, &mut access.allow, &mut self.access_input.allow);
+                            ui.end_row();
+                            ui.label("Deny: ");
+                            changed |=
+                                network_list(ui, &mut access.deny, &mut self.access_input.deny);
+                            ui.end_row();
+                        });
+
+                    if let Some(profile) = &mut self.listener_options.network {
+                        ui.add_space(10.0);
+                        ui.heading("Network");
+                        ui.add_space(10.0);
+
+                        let before = profile.clone();
+                        egui::Grid::new("network_form")
+                            .min_col_width(100.0)
+                            .num_columns(2)
+                            .spacing(vec2(0.0, 10.0))
+                            .show(ui, |ui| {
+                                ui.label("Download kbps: ");
+                                optional_value(ui, &mut profile.download_kbps, 1_000);
+                                ui.end_row();
+                                ui.label("Upload kbps: ");
+                                optional_value(ui, &mut profile.upload_kbps, 1_000);
+                                ui.end_row();
+                                ui.label("Latency ms: ");
+                                ui.add(
+                                    egui::DragValue::new(&mut profile.latency_ms).range(0..=10_000),
+                                );
+                                ui.end_row();
+                            });
+                        if *profile != before {
+                            profile.name = "Custom".to_owned();
+                            changed = true;
+                        }
+                    }
+
+                    ui.add_space(10.0);
+                    ui.heading("Timeouts");
+                    ui.add_space(10.0);
+
+                    egui::Grid::new("timeouts_form")
+                        .min_col_width(100.0)
+                        .num_columns(2)
+                        .spacing(vec2(0.0, 10.0))
+                        .show(ui, |ui| {
+                            let timeouts = &mut self.listener_options.timeouts;
+
+                            ui.label("Connect seconds: ");
+                            changed |= optional_value(ui, &mut timeouts.connect_secs, 10);
+                            ui.end_row();
+                            ui.label("Idle seconds: ");
+                            changed |= optional_value(ui, &mut timeouts.idle_secs, 300);
+                            ui.end_row();
+                            ui.label("Lifetime seconds: ");
+                            changed |= optional_value(ui, &mut timeouts.lifetime_secs, 3600);
+                            ui.end_row();
+                        });
+
+                    ui.add_space(10.0);
+                    ui.heading("Sockets");
+                    ui.add_space(10.0);
+
+                    egui::Grid::new("socket_form")
+                        .min_col_width(100.0)
+                        .num_columns(2)
+                        .spacing(vec2(0.0, 10.0))
+                        .show(ui, |ui| {
+                            let socket = &mut self.listener_options.socket;
+
+                            ui.label("No delay: ");
+                            changed |= ui
+                                .checkbox(&mut socket.nodelay, "Disable Nagle's algorithm")
+                                .changed();
+                            ui.end_row();
+
+                            ui.label("Keepalive: ");
+                            let mut enabled = socket.keepalive.is_some();
+                            if ui.checkbox(&mut enabled, "Probe idle connections").changed() {
+                                socket.keepalive = enabled.then(Keepalive::default);
+                                changed = true;
+                            }
+                            ui.end_row();
+                            if let Some(keepalive) = &mut socket.keepalive {
+                                ui.label("Probe after secs: ");
+                                changed |= ui
+                                    .add(
+                                        egui::DragValue::new(&mut keepalive.time_secs)
+                                            .range(1..=7200),
+                                    )
+                                    .changed();
+                                ui.end_row();
+                                ui.label("Probe interval secs: ");
+                                changed |= optional_value(ui, &mut keepalive.interval_secs, 10);
+                                ui.end_row();
+                                ui.label("Probe retries: ");
+                                changed |= optional_value(ui, &mut keepalive.retries, 5);
+                                ui.end_row();
+                            }
+
+                            ui.label("Send buffer bytes: ");
+                            changed |= optional_value(ui, &mut socket.send_buffer, 262_144);
+                            ui.end_row();
+                            ui.label("Receive buffer bytes: ");
+                            changed |= optional_value(ui, &mut socket.recv_buffer, 262_144);
+                            ui.end_row();
+
+                            // Only read when binding, so they cannot change while listening
+                            ui.label("Reuse port: ");
+                            changed |= ui
+                                .add_enabled(
+                                    !self.is_enabled,
+                                    egui::Checkbox::new(
+                                        &mut socket.reuse_port,
+                                        "Share the ports with another instance",
+                                    ),
+                                )
+                                .changed();
+                            ui.end_row();
+                            ui.label("Backlog: ");
+                            ui.add_enabled_ui(!self.is_enabled, |ui| {
+                                changed |= optional_value(ui, &mut socket.backlog, 1024);
+                            });
+                            ui.end_row();
+                        });
+
+                    if matches!(
+                        self.listener_options.mode,
+                        ListenerMode::Forward | ListenerMode::Http
+                    ) {
+                        ui.add_space(10.0);
+                        ui.heading("Client affinity");
+                        ui.label("Clients keep the port they used after switching to another one");
+                        ui.add_space(10.0);
+
+                        let options = &mut self.listener_options;
+                        ui.horizontal(|ui| {
+                            ui.label("Client affinity seconds: ");
+                            changed |= optional_value(ui, &mut options.affinity_secs, 300);
+                        });
+                    }
+
+                    ui.add_space(10.0);
+                    ui.heading("Retries");
+                    ui.add_space(10.0);
+
+                    egui::Grid::new("retry_form")
+                        .min_col_width(100.0)
+                        .num_columns(2)
+                        .spacing(vec2(0.0, 10.0))
+                        .show(ui, |ui| {
+                            let options = &mut self.listener_options;
+
+                            ui.label("Attempts: ");
+                            changed |= ui
+                                .add(
+                                    egui::DragValue::new(&mut options.retry.attempts).range(0..=20),
+                                )
+                                .changed();
+                            ui.end_row();
+                            ui.label("First backoff ms: ");
+                            changed |= ui
+                                .add(egui::DragValue::new(&mut options.retry.initial_backoff_ms))
+                                .changed();
+                            ui.end_row();
+                            ui.label("Max backoff ms: ");
+                            changed |= ui
+                                .add(egui::DragValue::new(&mut options.retry.max_backoff_ms))
+                                .changed();
+                            ui.end_row();
+
+                            ui.label("Fallback: ");
+                            let selected = self
+                                .forward_ports
+                                .iter()
+                                .find(|port| Some(&port.target) == options.fallback.as_ref())
+                                .map_or("None".to_owned(), |port| port.name.clone());
+                            egui::ComboBox::from_id_source("fallback_target")
+                                .selected_text(selected)
+                                .show_ui(ui, |ui| {
+                                    changed |= ui
+                                        .selectable_value(&mut options.fallback, None, "None")
+                                        .changed();
+                                    for port in &self.forward_ports {
+                                        changed |= ui
+                                            .selectable_value(
+                                                &mut options.fallback,
+                                                Some(port.target.clone()),
+                                                &port.name,
+                                            )
+                                            .changed();
+                                    }
+                                });
+                            ui.end_row();
+                        });
+
+                    ui.add_space(10.0);
+                    ui.heading("Faults");
+                    ui.label("Toggled from the list page");
+                    ui.add_space(10.0);
+
+                    egui::Grid::new("faults_form")
+                        .min_col_width(100.0)
+                        .num_columns(2)
+                        .spacing(vec2(0.0, 10.0))
+                        .show(ui, |ui| {
+                            let faults = &mut self.listener_options.faults;
+
+                            ui.label("Refuse %: ");
+                            changed |= ui
+                                .add(egui::DragValue::new(&mut faults.refuse.value).range(1..=100))
+                                .changed();
+                            ui.end_row();
+                            ui.label("Reset after bytes: ");
+                            changed |= ui
+                                .add(egui::DragValue::new(&mut faults.reset_after_bytes.value).range(1..=u64::MAX))
+                                .changed();
+                            ui.end_row();
+                            ui.label("Reset after seconds: ");
+                            changed |= ui
+                                .add(egui::DragValue::new(&mut faults.reset_after_secs.value).range(1..=u64::MAX))
+                                .changed();
+                            ui.end_row();
+                            ui.label("Corrupt %: ");
+                            changed |= ui
+                                .add(egui::DragValue::new(&mut faults.corrupt.value).range(1..=100))
+                                .changed();
+                            ui.end_row();
+                        });
+
+                    ui.add_space(10.0);
+                    ui.heading("Limits");
+                    ui.label("Applied when the listener is turned on");
+                    ui.add_space(10.0);
+
+                    ui.add_enabled_ui(!self.is_enabled, |ui| {
+                        egui::Grid::new("limits_form")
+                            .min_col_width(100.0)
+                            .num_columns(2)
+                            .spacing(vec2(0.0, 10.0))
+                            .show(ui, |ui| {
+                                let limits = &mut self.listener_options.limits;
+
+                                ui.label("Connections: ");
+                                changed |= optional_value(ui, &mut limits.max_connections, 100);
+                                ui.end_row();
+                                ui.label("Per client IP: ");
+                                changed |= optional_value(ui, &mut limits.max_per_ip, 10);
+                                ui.end_row();
+                                ui.label("New per second: ");
+                                changed |= optional_value(ui, &mut limits.max_per_second, 50);
+                                ui.end_row();
+                                ui.label("When exceeded: ");
+                                ui.horizontal(|ui| {
+                                    changed |= ui
+                                        .radio_value(
+                                            &mut limits.action,
+                                            LimitAction::Queue,
+                                            "Queue",
+                                        )
+                                        .changed();
+                                    changed |= ui
+                                        .radio_value(
+                                            &mut limits.action,
+                                            LimitAction::Refuse,
+                                            "Refuse",
+                                        )
+                                        .changed();
+                                });
+                                ui.end_row();
+                            });
+                    });
+
+                    if changed {
+                        self.update_backend();
+                    }
+                });
+            });
+        });
+    }
+}
+
+fn optional_value<T: Numeric>(ui: &mut Ui, value: &mut Option<T>, default: T) -> bool {
+    let mut enabled = value.is_some();
+    let mut changed = false;
+    ui.horizontal(|ui| {
+        changed |= ui.checkbox(&mut enabled, "").changed();
+        let mut current = value.unwrap_or(default);
+        changed |= ui
+            .add_enabled(
+                enabled,
+                egui::DragValue::new(&mut current).range(T::from_f64(1.0)..=T::MAX),
+            )
+            .changed();
+        *value = enabled.then_some(current);
+    });
+    changed
+}
+
+impl App {
+    fn override_table(&mut self, ui: &mut Ui) -> bool {
+        let mut changed = false;
+        let mut removed = None;
+        egui::Grid::new("override_table")
+            .num_columns(3)
+            .spacing(vec2(10.0, 10.0))
+            .show(ui, |ui| {
+                for (index, route) in self.overrides.iter_mut().enumerate() {
+                    changed |= ui
+                        .add(
+                            egui::TextEdit::singleline(&mut route.name)
+                                .hint_text("api.example.com:443"),
+                        )
+                        .changed();
+
+                    let selected = self
+                        .forward_ports
+                        .iter()
+                        .find(|port| port.target == route.target)
+                        .map_or("Choose a port".to_owned(), |port| port.name.clone());
+                    egui::ComboBox::from_id_source(("override_target", index))
+                        .selected_text(selected)
+                        .show_ui(ui, |ui| {
+                            for port in &self.forward_ports {
+                                changed |= ui
+                                    .selectable_value(
+                                        &mut route.target,
+                                        port.target.clone(),
+                                        &port.name,
+                                    )
+                                    .changed();
+                            }
+                        });
+
+                    if ui.small_button("x").clicked() {
+                        removed = Some(index);
+                    }
+                    ui.end_row();
+                }
+            });
+
+        if let Some(index) = removed {
+            self.overrides.remove(index);
+            changed = true;
+        }
+        if ui.button("Add override").clicked() {
+            self.overrides.push(Route {
+                name: String::new(),
+                target: self
+                    .forward_ports
+                    .first()
+                    .map(|port| port.target.clone())
+                    .unwrap_or_default(),
+            });
+        }
+        changed
+    }
+}
+
+fn network_list(ui: &mut Ui, networks: &mut Vec<IpNet>, input: &mut String) -> bool {
+    let mut changed = false;
+    ui.vertical(|ui| {
+        let mut removed = None;
+        for (index, network) in networks.iter().enumerate() {
+            ui.horizontal(|ui| {
+                ui.label(network.to_string());
+                if ui.small_button("x").clicked() {
+                    removed = Some(index);
+                }
+            });
+        }
+        if let Some(index) = removed {
+            networks.remove(index);
+            changed = true;
+        }
+
+        ui.horizontal(|ui| {
+            let parsed = parse_network(input);
+            ui.add(egui::TextEdit::singleline(input).hint_text("10.0.0.0/8"));
+            if ui
+                .add_enabled(parsed.is_some(), egui::Button::new("Add"))
+                .clicked()
+            {
+                networks.extend(parsed);
+                input.clear();
+                changed = true;
+            }
+            if parsed.is_none() && !input.trim().is_empty() {
+                ui.label(
+                    RichText::new("Invalid address")
+                        .small()
+                        .color(ui.visuals().warn_fg_color),
+                );
+            }
+        });
+    });
+    changed
+}
+
+fn parse_network(input: &str) -> Option<IpNet> {
+    let input = input.trim();
+    input
+        .parse::<IpNet>()
+        .ok()
+        .or_else(|| input.parse::<IpAddr>().ok().map(IpNet::from))
+}
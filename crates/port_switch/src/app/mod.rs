@@ -1,8 +1,20 @@
-use dynamic_tcp_proxy::{DynamicProxy, ForwardTarget, ProxyConfig};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use dynamic_tcp_proxy::{
+    DynamicProxy, ForwardTarget, ListenerMode, ListenerOptions, ProxyConfig, ProxyEvent, Route,
+    TargetKind,
+};
 use eframe::egui;
 
+use crate::logging::{self, LogLevel};
+
 mod create;
+mod inspector;
 mod list;
+mod listener;
+
+const EVENT_HISTORY: usize = 100;
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 struct ForwardPort {
@@ -18,6 +30,24 @@ impl PartialEq for ForwardPort {
     }
 }
 
+impl ForwardPort {
+    /// Pseudo-targets listed after the configured ports to simulate outages.
+    fn builtins() -> [ForwardPort; 2] {
+        let builtin = |name: &str, kind| ForwardPort {
+            target: ForwardTarget {
+                kind,
+                ..Default::default()
+            },
+            name: name.to_owned(),
+            error: None,
+        };
+        [
+            builtin("Reject", TargetKind::Reject),
+            builtin("Blackhole", TargetKind::Blackhole),
+        ]
+    }
+}
+
 impl Default for ForwardPort {
     fn default() -> Self {
         Self {
@@ -37,12 +67,38 @@ pub struct App {
     is_enabled: bool,
     forward_ports: Vec<ForwardPort>,
     active_forward_port: Option<ForwardPort>,
+    #[serde(default)]
+    listener_options: ListenerOptions,
+    /// Destinations sent to a forward port in SOCKS5 and HTTP CONNECT modes
+    #[serde(default)]
+    overrides: Vec<Route>,
+    #[serde(default)]
+    log_level: LogLevel,
     #[serde(skip)]
     active_page: Pages,
     #[serde(skip)]
     proxy_handle: Option<DynamicProxy>,
     #[serde(skip)]
     error: Option<String>,
+    #[serde(skip)]
+    events: VecDeque<ProxyEvent>,
+    #[serde(skip)]
+    inspection: inspector::Inspection,
+    #[serde(skip)]
+    access_input: listener::AccessInput,
+}
+
+/// Target of the port the GUI last saved under `name`, the built-in pseudo-targets included.
+pub(crate) fn saved_target(name: &str) -> Option<ForwardTarget> {
+    let path = eframe::storage_dir(crate::APP_NAME)?.join("app.ron");
+    let stored: HashMap<String, String> =
+        ron::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+    let app: App = ron::from_str(stored.get(eframe::APP_KEY)?).ok()?;
+    app.forward_ports
+        .into_iter()
+        .chain(ForwardPort::builtins())
+        .find(|port| port.name == name)
+        .map(|port| port.target)
 }
 
 impl App {
@@ -62,6 +118,7 @@ impl App {
             Self::init_state()
         };
         init_app_state.proxy_handle = Some(proxy_handle);
+        logging::set_level(init_app_state.log_level);
         init_app_state.update_backend();
         init_app_state
     }
@@ -72,17 +129,43 @@ enum Pages {
     List,
     Creation(ForwardPort),
     Edit(usize, ForwardPort),
+    Listener,
+    Inspector,
 }
 
 impl App {
     fn update_backend(&mut self) {
         let mut conf = ProxyConfig::default();
-        if self.is_enabled && self.active_forward_port.is_some() {
-            let listen_port = self.listen_port;
-            if let Some(fp) = &self.active_forward_port {
-                let forward_port = fp.target.clone();
-                conf = ProxyConfig(Some((listen_port, forward_port)));
-            }
+        // SOCKS5 and HTTP CONNECT clients pick their own destination, so no active port is needed
+        let forward_port = match (&self.active_forward_port, self.listener_options.mode) {
+            (Some(fp), _) => Some(fp.target.clone()),
+            (None, ListenerMode::Forward | ListenerMode::Http) => None,
+            (None, _) => Some(ForwardTarget::default()),
+        };
+        if let (true, Some(forward_port)) = (self.is_enabled, forward_port) {
+            let mut options = self.listener_options.clone();
+            let builtins = ForwardPort::builtins();
+            let port_routes = self
+                .forward_ports
+                .iter()
+                .chain(&builtins)
+                .map(|port| Route {
+                    name: port.name.clone(),
+                    target: port.target.clone(),
+                });
+            options.routes = self
+                .overrides
+                .iter()
+                .filter(|route| !route.name.is_empty())
+                .cloned()
+                .chain(port_routes)
+                .collect();
+            options.listed_targets = self
+                .forward_ports
+                .iter()
+                .map(|port| port.name.clone())
+                .collect();
+            conf = ProxyConfig::forward(self.listen_port, forward_port, options);
         }
 
         match conf.validate() {
@@ -102,12 +185,31 @@ impl App {
     }
 }
 
+impl App {
+    fn poll_events(&mut self) {
+        let Some(backend) = &self.proxy_handle else {
+            return;
+        };
+        for event in backend.events() {
+            if self.events.len() == EVENT_HISTORY {
+                self.events.pop_front();
+            }
+            self.events.push_back(event);
+        }
+    }
+}
+
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.poll_events();
+        ctx.request_repaint_after(Duration::from_secs(1));
+
         match &self.active_page {
             Pages::List => {
                 self.list_page(ctx);
             }
+            Pages::Listener => self.listener_page(ctx),
+            Pages::Inspector => self.inspector_page(ctx),
             _ => self.creation_page(ctx),
         }
     }
@@ -1,4 +1,13 @@
 mod app;
+mod logging;
+mod replay;
+mod serve;
 mod widgets;
 pub use app::App;
 pub use dynamic_tcp_proxy::{DynamicProxy, ProxyConfig};
+pub use logging::init_logging;
+pub use replay::replay_command;
+pub use serve::serve_command;
+
+/// Title of the window, also naming the folder the settings are saved in
+pub const APP_NAME: &str = "Port switch";
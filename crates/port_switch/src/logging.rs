@@ -0,0 +1,64 @@
+use std::sync::OnceLock;
+
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// How much the proxy logs, other crates only log warnings and errors.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, PartialEq, Clone, Copy)]
+pub(crate) enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub(crate) const ALL: [LogLevel; 6] = [
+        LogLevel::Off,
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+
+    fn filter(self) -> EnvFilter {
+        let level = match self {
+            LogLevel::Off => return EnvFilter::new("off"),
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        };
+        EnvFilter::new(format!("warn,dynamic_tcp_proxy={}", level))
+    }
+}
+
+/// Logs to stderr, filtered by `RUST_LOG` when set, by the level picked in the settings otherwise.
+pub fn init_logging() {
+    let (filter, from_env) = match EnvFilter::try_from_default_env() {
+        Ok(filter) => (filter, true),
+        Err(_) => (LogLevel::default().filter(), false),
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .init();
+    if !from_env {
+        let _ = FILTER.set(handle);
+    }
+}
+
+/// Applies the level picked in the settings, unless `RUST_LOG` decides.
+pub(crate) fn set_level(level: LogLevel) {
+    if let Some(handle) = FILTER.get() {
+        let _ = handle.reload(level.filter());
+    }
+}
@@ -1,7 +1,24 @@
 use port_switch::DynamicProxy;
 
 fn main() -> Result<(), eframe::Error> {
-    // env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`).
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("replay") {
+        if let Err(err) = port_switch::replay_command(&args[1..]) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    port_switch::init_logging(); // Log to stderr, `RUST_LOG=debug` overrides the settings
+
+    if args.first().map(String::as_str) == Some("serve") {
+        if let Err(err) = port_switch::serve_command(&args[1..]) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
     let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
@@ -14,7 +31,7 @@ fn main() -> Result<(), eframe::Error> {
         DynamicProxy::initiate().map_err(|err| eframe::Error::AppCreation(Box::new(err)))?;
 
     eframe::run_native(
-        "Port switch",
+        port_switch::APP_NAME,
         native_options,
         Box::new(|cc| Ok(Box::new(port_switch::App::new(cc, proxy_handle)))),
     )?;
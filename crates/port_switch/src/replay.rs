@@ -0,0 +1,49 @@
+use std::path::{Path, PathBuf};
+
+use dynamic_tcp_proxy::{replay, Direction, Session};
+
+use crate::serve::parse_target;
+
+const USAGE: &str =
+    "Usage: port_switch replay <session file> <forward port name | host:port> [output file]";
+
+/// Replays a recorded session against another target, storing what was sent
+/// and the responses next to the recording unless an output file is given. The target is
+/// looked up among the forward ports saved by the GUI before being read as an address.
+pub fn replay_command(args: &[String]) -> Result<(), String> {
+    let (session_path, target, output) = match args {
+        [session, target] => (session, target, None),
+        [session, target, output] => (session, target, Some(output)),
+        _ => return Err(USAGE.to_owned()),
+    };
+
+    let target = crate::app::saved_target(target)
+        .or_else(|| parse_target(target))
+        .ok_or(USAGE)?;
+    let session_path = Path::new(session_path);
+    let output = output
+        .map(PathBuf::from)
+        .unwrap_or_else(|| session_path.with_extension("replay.pssession"));
+
+    let session = Session::load(session_path)
+        .map_err(|e| format!("Cannot read {}: {}", session_path.display(), e))?;
+    let replayed = replay(&session, &target)
+        .map_err(|e| format!("Replay against {} failed: {}", target, e))?;
+    replayed
+        .save(&output)
+        .map_err(|e| format!("Cannot write {}: {}", output.display(), e))?;
+
+    let received: usize = replayed
+        .chunks
+        .iter()
+        .filter(|chunk| chunk.direction == Direction::ServerToClient)
+        .map(|chunk| chunk.data.len())
+        .sum();
+    println!(
+        "Replayed {} chunks, received {} bytes, saved to {}",
+        session.chunks.len(),
+        received,
+        output.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use dynamic_tcp_proxy::{DynamicProxy, ForwardTarget, ListenerOptions, ProxyConfig};
+
+const USAGE: &str = "Usage: port_switch serve <listen port | activated socket name> <host:port>";
+
+/// Forwards without the GUI until the process is stopped. A socket name instead of a port
+/// listens on the sockets systemd passed under that name.
+pub fn serve_command(args: &[String]) -> Result<(), String> {
+    let [listen, target] = args else {
+        return Err(USAGE.to_owned());
+    };
+    let target = parse_target(target).ok_or(USAGE)?;
+    let config = match listen.parse::<u16>() {
+        Ok(port) => ProxyConfig::forward(port, target, ListenerOptions::default()),
+        Err(_) => ProxyConfig::forward(
+            0,
+            target,
+            ListenerOptions {
+                activated_socket: Some(listen.clone()),
+                ..Default::default()
+            },
+        ),
+    };
+    config.validate()?;
+
+    let (proxy, handle) =
+        DynamicProxy::initiate().map_err(|e| format!("Cannot start the proxy: {}", e))?;
+    proxy
+        .update(config)
+        .map_err(|_| "Proxy stopped".to_owned())?;
+    // Runs for as long as the proxy is kept
+    let _ = handle.join();
+    drop(proxy);
+    Ok(())
+}
+
+pub(crate) fn parse_target(target: &str) -> Option<ForwardTarget> {
+    let (domain, port) = target.rsplit_once(':')?;
+    Some(ForwardTarget {
+        domain: domain.to_owned(),
+        port: port.parse().ok()?,
+        ..Default::default()
+    })
+}